@@ -12,15 +12,68 @@ pub struct Args {
     #[arg(short, long, action = clap::ArgAction::Count)]
     pub verbose: u8,
 
+    /// Emit logs as JSON lines
+    #[arg(long)]
+    pub log_json: bool,
+
     /// Run in daemon mode
     #[arg(long)]
     pub daemon: bool,
 
+    /// Write the daemon's PID to this file, refusing to start if it names a running process
+    #[arg(long, requires = "daemon")]
+    pub pidfile: Option<String>,
+
+    /// Run the daemon but log notifications instead of sending them
+    #[arg(long, requires = "daemon")]
+    pub dry_run: bool,
+
     /// Check if currently added feeds are valid
     #[arg(long)]
     pub check: bool,
 
+    /// Check feed URLs read from stdin, one per line, instead of the configured feeds
+    #[arg(long, requires = "check")]
+    pub stdin: bool,
+
+    /// Validate the config file and exit
+    #[arg(long)]
+    pub validate: bool,
+
+    /// Print the effective config, after environment overrides, as JSON and exit. Secrets are redacted
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// Write a commented default config file and exit
+    #[arg(long)]
+    pub init: bool,
+
+    /// Where --init writes the config, defaults to --config
+    #[arg(long, requires = "init")]
+    pub output: Option<String>,
+
+    /// Let --init overwrite an existing file
+    #[arg(long, requires = "init")]
+    pub force: bool,
+
+    /// Confirm destructive --cli commands such as feed forget
+    #[arg(long)]
+    pub yes: bool,
+
+    /// Print --cli replies as JSON: {"status": "ok"|"error", "data": ...}
+    #[arg(long)]
+    pub json: bool,
+
+    /// Print without colors. NO_COLOR does the same, and colors are off when stdout is not a
+    /// terminal unless CLICOLOR_FORCE is set
+    #[arg(long)]
+    pub no_color: bool,
+
     /// For communicating with a running instance of self
     #[arg(long, num_args = 1..)]
     pub cli: Vec<String>,
+
+    /// Open an interactive prompt for sending commands to a running instance
+    #[arg(long, conflicts_with = "cli")]
+    pub repl: bool,
 }
@@ -1,22 +1,52 @@
-use crate::{config::AppConfig, feeds::watcher::resolve_feeds, server::ServerCommand};
+use crate::{
+    config::{AppConfig, ConfigIssue, FeedSpec, redact_url},
+    feeds::{feed::HostLimiter, watcher::resolve_feeds},
+    server::{
+        JSON_MARKER, ServerCommand, VERSION, local_socket_name, quote_arg, read_message,
+        write_frame,
+    },
+};
 use colored::*;
+use rustyline::{DefaultEditor, error::ReadlineError};
+use serde_json::Value;
 use spinners::{Spinner, Spinners};
-use std::io;
-use tokio::io::AsyncReadExt;
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, BufReader};
 use {
-    interprocess::local_socket::{
-        GenericNamespaced,
-        tokio::{Stream, prelude::*},
-    },
-    tokio::io::AsyncWriteExt,
+    interprocess::local_socket::tokio::{Stream, prelude::*},
+    tokio::{io::AsyncWriteExt, net::TcpStream},
 };
 
-pub async fn check_feeds(feeds: Vec<String>, v: u8) {
+/// Reads one feed URL per line, skipping blank lines and `#` comments
+pub fn read_feed_list(reader: impl io::BufRead) -> Vec<FeedSpec> {
+    reader
+        .lines()
+        .map_while(Result::ok)
+        .map(|l| l.trim().to_string())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(FeedSpec::from)
+        .collect()
+}
+
+pub async fn check_feeds(
+    feeds: Vec<FeedSpec>,
+    cache_size: usize,
+    max_body: usize,
+    max_concurrent: usize,
+    min_host_interval: Duration,
+    v: u8,
+) {
     let mut sp = Spinner::new(Spinners::Dots, "Checking feeds".blue().bold().to_string());
-    let (feeds, failed_feeds) = resolve_feeds(feeds).await;
+    let hosts = HostLimiter::new(min_host_interval);
+    let (feeds, failed_feeds) =
+        resolve_feeds(feeds, cache_size, max_body, max_concurrent, &hosts).await;
     sp.stop();
 
-    let succesful_feeds: Vec<String> = feeds.iter().map(|f| f.source()).collect();
+    let succesful_feeds: Vec<String> = feeds.iter().map(|f| redact_url(&f.source())).collect();
+    let failed_feeds: Vec<String> = failed_feeds.iter().map(|f| redact_url(&f.url)).collect();
     let succeed = succesful_feeds.len();
     let failed = failed_feeds.len();
 
@@ -45,26 +75,405 @@ pub async fn check_feeds(feeds: Vec<String>, v: u8) {
     }
 }
 
-pub async fn send_command(cfg: AppConfig, command: ServerCommand) -> io::Result<()> {
-    let name = cfg.socket.to_ns_name::<GenericNamespaced>()?;
-    let mut conn = Stream::connect(name).await?;
-    let cmd = command.to_string();
+/// Prints config issues and returns whether any of them is fatal
+pub fn report_config_issues(issues: &[ConfigIssue]) -> bool {
+    for issue in issues {
+        let label = if issue.fatal {
+            "error:".red().bold()
+        } else {
+            "warning:".yellow().bold()
+        };
+        println!("{} {}", label, issue.message);
+    }
+
+    let fatal = issues.iter().filter(|i| i.fatal).count();
+    if issues.is_empty() {
+        println!("{}", "Config is valid".green().bold());
+    } else {
+        println!(
+            "\n{} {} errors, {} warnings",
+            "Validation finished:".bold().underline(),
+            fatal,
+            issues.len() - fatal
+        );
+    }
+    fatal > 0
+}
 
-    conn.write_all(cmd.as_bytes()).await?;
-    if !cmd.ends_with('\n') {
-        conn.write_all(b"\n").await?;
+/// Joins `--cli` arguments into a command line. A single argument is the whole command, as in
+/// `--cli "feed add <url>"`. Several are shell arguments, those that contain spaces stay one
+/// argument on the daemon side.
+pub fn command_line(args: &[String]) -> String {
+    match args {
+        [line] => line.clone(),
+        args => args
+            .iter()
+            .map(|a| quote_arg(a))
+            .collect::<Vec<_>>()
+            .join(" "),
     }
+}
+
+/// With `json` the daemon's JSON reply is printed as is, for scripts
+pub async fn send_command(cfg: AppConfig, command: ServerCommand, json: bool) -> io::Result<()> {
+    let is_ping = matches!(command, ServerCommand::Ping);
+    let start = Instant::now();
+    if let Some(addr) = &cfg.tcp {
+        let conn = TcpStream::connect(addr).await?;
+        exchange(conn, command, cfg.auth_token.as_deref(), json).await?;
+    } else {
+        let conn = Stream::connect(local_socket_name(&cfg.socket)?).await?;
+        exchange(conn, command, cfg.auth_token.as_deref(), json).await?;
+    }
+
+    // Includes connecting, so it reflects what any client would see
+    if is_ping && !json {
+        println!("{} {:.2?}", "Round trip:".bold(), start.elapsed());
+    }
+    Ok(())
+}
+
+async fn exchange<S>(
+    mut conn: S,
+    command: ServerCommand,
+    auth_token: Option<&str>,
+    json: bool,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let cmd = request_line(&command, json);
+
+    // The daemon expects the token as its own message before the command
+    if let Some(token) = auth_token {
+        write_frame(&mut conn, token).await?;
+    }
+    write_frame(&mut conn, &cmd).await?;
     conn.flush().await?;
 
-    let mut buffer = String::with_capacity(2048);
-    conn.read_to_string(&mut buffer).await?;
+    let mut reader = BufReader::new(conn);
+    let Some((mut buffer, framed)) = read_message(&mut reader).await? else {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "daemon closed the connection without replying",
+        ));
+    };
+    // Daemons from before framing reply with plain text until they close the connection
+    if !framed {
+        buffer.push('\n');
+        reader.read_to_string(&mut buffer).await?;
+    }
+    print_reply(&command, &buffer, json);
+    Ok(())
+}
+
+/// Commands whose reply the CLI always asks for as JSON and renders itself
+fn renders_json(command: &ServerCommand) -> bool {
+    matches!(command, ServerCommand::GetFeeds)
+}
+
+fn request_line(command: &ServerCommand, json: bool) -> String {
+    if json || renders_json(command) {
+        format!("{} {}", JSON_MARKER, command)
+    } else {
+        command.to_string()
+    }
+}
+
+fn print_reply(command: &ServerCommand, buffer: &str, json: bool) {
+    if json {
+        println!("{}", buffer.trim_end());
+        return;
+    }
+    if renders_json(command) {
+        print!("{}", render_feeds(buffer));
+        return;
+    }
 
-    let result = match &buffer[..4] {
-        "ACK " => "Ok: ".green().bold(),
-        "ERR " => "Error: ".red().bold(),
-        _ => "Unknown: ".yellow().bold(),
+    let tabular = matches!(command, ServerCommand::FeedStats);
+    let is_version = matches!(command, ServerCommand::Version);
+    let (result, message) = reply_status(buffer);
+    if tabular && buffer.starts_with("ACK") {
+        print_stats(result, message);
+    } else if is_version && buffer.starts_with("ACK") {
+        print_versions(message.trim());
+    } else {
+        println!("{} {}", result, message);
+    }
+}
+
+/// Splits the status off a reply, replies without a known status are shown whole
+fn reply_status(buffer: &str) -> (ColoredString, &str) {
+    match buffer.split_once(' ') {
+        Some(("ACK", rest)) => ("Ok: ".green().bold(), rest),
+        Some(("ERR", rest)) => ("Error: ".red().bold(), rest),
+        _ => ("Unknown: ".yellow().bold(), buffer),
+    }
+}
+
+/// Opens an interactive prompt on one connection to the daemon. Commands are read with line
+/// editing and history until `quit`, `exit` or EOF.
+pub async fn repl(cfg: AppConfig, json: bool) -> io::Result<()> {
+    let not_running = |target: &str, e: io::Error| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "could not connect to {} ({}), is the daemon running?",
+                target, e
+            ),
+        )
     };
-    println!("{} {}", result, &buffer[4..]);
+    if let Some(addr) = &cfg.tcp {
+        let conn = TcpStream::connect(addr)
+            .await
+            .map_err(|e| not_running(addr, e))?;
+        repl_session(conn, cfg.auth_token.as_deref(), json).await
+    } else {
+        let conn = Stream::connect(local_socket_name(&cfg.socket)?)
+            .await
+            .map_err(|e| not_running(&cfg.socket, e))?;
+        repl_session(conn, cfg.auth_token.as_deref(), json).await
+    }
+}
 
-    Ok(())
+async fn repl_session<S>(conn: S, auth_token: Option<&str>, json: bool) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut editor = DefaultEditor::new().map_err(io::Error::other)?;
+    let mut conn = BufReader::new(conn);
+    if let Some(token) = auth_token {
+        write_frame(conn.get_mut(), token).await?;
+    }
+    println!(
+        "{} type help for the supported commands, quit to leave",
+        "Connected,".green().bold()
+    );
+
+    loop {
+        // Waiting for input blocks, which is fine as nothing else runs on this side
+        let line = match tokio::task::block_in_place(|| editor.readline("rssd> ")) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => return Ok(()),
+            Err(e) => return Err(io::Error::other(e)),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line);
+        if line == "quit" || line == "exit" {
+            return Ok(());
+        }
+
+        let command = match ServerCommand::try_from(line.to_string()) {
+            Ok(command) => command,
+            Err(e) => {
+                println!("{} {}", "Error parsing command:".red().bold(), e);
+                continue;
+            }
+        };
+        if matches!(command, ServerCommand::ForgetFeed(..)) {
+            let confirm = tokio::task::block_in_place(|| {
+                editor.readline("feed forget deletes the feed's history, type yes to confirm: ")
+            });
+            if !confirm.is_ok_and(|answer| answer.trim() == "yes") {
+                continue;
+            }
+        }
+
+        let start = Instant::now();
+        write_frame(conn.get_mut(), &request_line(&command, json)).await?;
+        conn.get_mut().flush().await?;
+        let Some((reply, _)) = read_message(&mut conn).await? else {
+            println!("{}", "The daemon closed the connection".red().bold());
+            return Ok(());
+        };
+        print_reply(&command, &reply, json);
+        if matches!(command, ServerCommand::Ping) && !json {
+            println!("{} {:.2?}", "Round trip:".bold(), start.elapsed());
+        }
+    }
+}
+
+/// Renders a JSON `list` reply as one feed per line, named by its title when it has one, with
+/// its state colored and the URL and refresh details below
+fn render_feeds(buffer: &str) -> String {
+    let Ok(reply) = serde_json::from_str::<Value>(buffer.trim()) else {
+        return format!("{} {}\n", "Unknown: ".yellow().bold(), buffer);
+    };
+    let data = &reply["data"];
+    let Some(feeds) = data.as_array().filter(|_| reply["status"] == "ok") else {
+        let message = data.as_str().map(str::to_string);
+        return format!(
+            "{} {}\n",
+            "Error: ".red().bold(),
+            message.unwrap_or_else(|| data.to_string())
+        );
+    };
+
+    let mut out = format!(
+        "{} Following {} feeds\n",
+        "Ok: ".green().bold(),
+        feeds.len()
+    );
+    for feed in feeds {
+        let url = feed["url"].as_str().unwrap_or_default();
+        let failures = feed["failures"].as_u64().unwrap_or_default();
+        let state = if feed["paused"] == true {
+            "paused".yellow()
+        } else if feed["stale"] == true {
+            "stale".red()
+        } else if failures > 0 {
+            "failing".red()
+        } else {
+            "ok".green()
+        };
+        match feed["title"].as_str() {
+            Some(title) => out += &format!("{:<8} {} {}\n", state, title.bold(), url.dimmed()),
+            None => out += &format!("{:<8} {}\n", state, url.bold()),
+        }
+        let last = feed["last_success"].as_str().unwrap_or("never");
+        out += &format!("{:<8} last refresh {}, {} failures\n", "", last, failures);
+    }
+    out
+}
+
+/// Shows the daemon's version next to this binary's, a mismatch usually means the daemon was not
+/// restarted after an upgrade
+fn print_versions(daemon: &str) {
+    println!("{} {}", "Daemon version:".bold(), daemon);
+    println!("{} {}", "Client version:".bold(), VERSION);
+    if daemon != VERSION {
+        println!(
+            "{} the daemon runs a different version than this client, restart it after upgrading",
+            "Warning:".yellow().bold()
+        );
+    }
+}
+
+/// Prints `feed stats` rows, sent as `<count>\t<latest>\t<url>`, as aligned columns
+fn print_stats(result: ColoredString, message: &str) {
+    let mut lines = message.lines();
+    println!("{} {}", result, lines.next().unwrap_or_default());
+
+    let rows: Vec<Vec<&str>> = lines
+        .map(|l| l.splitn(3, '\t').collect::<Vec<_>>())
+        .filter(|cols| cols.len() == 3)
+        .collect();
+    let width = rows.iter().map(|c| c[0].len()).max().unwrap_or(0).max(5);
+    let latest_width = rows.iter().map(|c| c[1].len()).max().unwrap_or(0).max(11);
+    println!(
+        "{:>width$}  {:<latest_width$}  {}",
+        "Items".bold(),
+        "Latest item".bold(),
+        "Feed".bold(),
+        width = width,
+        latest_width = latest_width
+    );
+    for cols in rows {
+        println!(
+            "{:>width$}  {:<latest_width$}  {}",
+            cols[0],
+            cols[1],
+            cols[2],
+            width = width,
+            latest_width = latest_width
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_and_unknown_replies_are_shown_whole() {
+        for (reply, label, message) in [
+            ("ACK Added feed", "Ok: ", "Added feed"),
+            ("ERR busy, try again\n", "Error: ", "busy, try again\n"),
+            ("A", "Unknown: ", "A"),
+            ("", "Unknown: ", ""),
+            ("ACKé", "Unknown: ", "ACKé"),
+        ] {
+            let (result, rest) = reply_status(reply);
+            assert_eq!((&*result, rest), (label, message), "{:?}", reply);
+        }
+    }
+
+    /// Exchanges a ping with a daemon that answers with the raw bytes of `reply`
+    async fn ping_daemon_replying(reply: &'static [u8]) -> io::Result<()> {
+        let (client, daemon) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            let mut daemon = BufReader::new(daemon);
+            read_message(&mut daemon).await.unwrap();
+            daemon.get_mut().write_all(reply).await.unwrap();
+        });
+        exchange(client, ServerCommand::Ping, None, false).await
+    }
+
+    #[tokio::test]
+    async fn exchange_survives_tiny_and_missing_replies() {
+        for reply in [&b"#1\nA"[..], b"#0\n", b"A"] {
+            ping_daemon_replying(reply).await.unwrap();
+        }
+        let err = ping_daemon_replying(b"").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn renders_one_feed_per_line() {
+        colored::control::set_override(false);
+        let reply = r#"{"status":"ok","data":[
+            {"url":"https://example.com/rss","title":"Example Feed","failures":0,"paused":false,"stale":false,"last_success":"2024-03-05T14:30:00+00:00"},
+            {"url":"https://broken.example/rss","title":null,"failures":3,"paused":false,"stale":false,"last_success":null}
+        ]}"#;
+        assert_eq!(
+            render_feeds(reply),
+            "Ok:  Following 2 feeds\n\
+             ok       Example Feed https://example.com/rss\n\
+             \x20        last refresh 2024-03-05T14:30:00+00:00, 0 failures\n\
+             failing  https://broken.example/rss\n\
+             \x20        last refresh never, 3 failures\n"
+        );
+        assert_eq!(
+            render_feeds(r#"{"status":"error","data":"busy, try again"}"#),
+            "Error:  busy, try again\n"
+        );
+    }
+
+    #[test]
+    fn feed_list_skips_blank_lines_and_comments() {
+        let input = "\n# exported from my reader\n  https://example.com/rss  \n\n\t\nhttps://example.org/atom.xml\n";
+        let urls: Vec<String> = read_feed_list(input.as_bytes())
+            .into_iter()
+            .map(|spec| spec.url)
+            .collect();
+        assert_eq!(
+            urls,
+            ["https://example.com/rss", "https://example.org/atom.xml"]
+        );
+        assert!(read_feed_list(&b""[..]).is_empty());
+    }
+
+    #[test]
+    fn cli_takes_one_command_string_or_shell_arguments() {
+        let parse = |args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+            ServerCommand::try_from(command_line(&args)).map(|cmd| cmd.to_string())
+        };
+        assert_eq!(
+            parse(&["feed add https://x.example/rss"]).unwrap(),
+            "feed add https://x.example/rss"
+        );
+        assert_eq!(
+            parse(&["feed", "add", "https://x.example/rss"]).unwrap(),
+            "feed add https://x.example/rss"
+        );
+        assert_eq!(
+            parse(&["search", "rust async"]).unwrap(),
+            r#"search "rust async""#
+        );
+    }
 }
@@ -1,21 +1,215 @@
-use chrono::Utc;
-use log::error;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use log::{error, info, warn};
 use rss::{Category, Guid, Item};
-use sqlx::SqlitePool;
+use sqlx::{Sqlite, SqlitePool, Transaction};
+
+use crate::{
+    config::{canonical_url, redact_url},
+    feeds::feed::normalize_link,
+};
+use std::collections::HashMap;
 
 pub struct SeenStore {
     pool: SqlitePool,
+    /// Whether the FTS5 search index is available, searches fall back to LIKE otherwise
+    fts: bool,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct ArchivedItem {
+    pub title: Option<String>,
+    pub link: Option<String>,
+    pub pub_date: String,
+    pub feed_source: String,
+    pub enclosure_url: Option<String>,
+    pub enclosure_type: Option<String>,
+    pub enclosure_length: Option<i64>,
+}
+
+/// What is remembered about a feed between runs besides its options
+#[derive(Debug, Default)]
+pub struct FeedRecord {
+    /// Channel title from the last successful refresh
+    pub title: Option<String>,
+    pub last_success: Option<DateTime<Utc>>,
+}
+
+/// Archive totals for one feed source
+#[derive(Debug)]
+pub struct FeedItemStats {
+    pub items: i64,
+    /// RFC 3339 time the newest item was archived
+    pub latest: String,
+}
+
+/// Archived item with the columns needed to rebuild its notification, e.g. one that was
+/// picked up for notification but never confirmed as delivered
+#[derive(Debug, sqlx::FromRow)]
+pub struct PendingItem {
+    pub id: String,
+    pub title: Option<String>,
+    pub link: Option<String>,
+    pub description: Option<String>,
+    pub pub_date_raw: Option<String>,
+    pub feed_source: String,
+}
+
+impl PendingItem {
+    pub fn to_item(&self) -> Item {
+        let mut item = Item::default();
+        item.set_title(self.title.clone());
+        item.set_link(self.link.clone());
+        item.set_description(self.description.clone());
+        item.set_pub_date(self.pub_date_raw.clone());
+        item
+    }
+}
+
+/// Schema changes on top of the base tables, applied in order and tracked with `PRAGMA user_version`.
+/// Only ever append to this list.
+const MIGRATIONS: &[&str] = &[
+    "ALTER TABLE items_archive ADD COLUMN enclosure_url TEXT",
+    "ALTER TABLE items_archive ADD COLUMN enclosure_type TEXT",
+    "ALTER TABLE items_archive ADD COLUMN enclosure_length INTEGER",
+    "ALTER TABLE feeds ADD COLUMN disabled INTEGER NOT NULL DEFAULT 0",
+    "ALTER TABLE items_archive ADD COLUMN pub_date_raw TEXT",
+    "CREATE INDEX IF NOT EXISTS items_archive_feed_archived ON items_archive (feed_source, archived_at)",
+    "CREATE INDEX IF NOT EXISTS items_archive_archived ON items_archive (archived_at)",
+    "ALTER TABLE items_archive ADD COLUMN delivered INTEGER NOT NULL DEFAULT 1",
+    "ALTER TABLE feeds ADD COLUMN paused INTEGER NOT NULL DEFAULT 0",
+    "CREATE INDEX IF NOT EXISTS items_archive_pub_date ON items_archive (pub_date)",
+    "ALTER TABLE feeds ADD COLUMN options TEXT",
+    "ALTER TABLE feeds ADD COLUMN title TEXT",
+    "ALTER TABLE feeds ADD COLUMN last_success TEXT",
+];
+
+/// `user_version` reached by the migration indexing `pub_date`. Dates archived as raw strings
+/// before they were normalized are rewritten alongside it, otherwise the index would not sort.
+const PUB_DATE_INDEX_VERSION: usize = 10;
+
+/// Parses an RFC 822 (RSS) or RFC 3339 (Atom) date into UTC. Also accepts the common deviations
+/// seen in real feeds: spelled out weekdays, a space instead of `T` and missing time zones,
+/// which are taken as UTC.
+pub fn parse_pub_date(raw: &str) -> Option<DateTime<Utc>> {
+    let raw = raw.trim();
+    if let Ok(date) =
+        DateTime::parse_from_rfc2822(raw).or_else(|_| DateTime::parse_from_rfc3339(raw))
+    {
+        return Some(date.with_timezone(&Utc));
+    }
+
+    // The weekday is optional in RFC 822, dropping it gets past "Tuesday," and wrong weekdays
+    if let Some((_, rest)) = raw.split_once(',')
+        && let Ok(date) = DateTime::parse_from_rfc2822(rest.trim())
+    {
+        return Some(date.with_timezone(&Utc));
+    }
+
+    for format in ["%Y-%m-%d %H:%M:%S%.f%:z", "%Y-%m-%d %H:%M:%S%.f %z"] {
+        if let Ok(date) = DateTime::parse_from_str(raw, format) {
+            return Some(date.with_timezone(&Utc));
+        }
+    }
+    for format in ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"] {
+        if let Ok(date) = NaiveDateTime::parse_from_str(raw, format) {
+            return Some(date.and_utc());
+        }
+    }
+    NaiveDate::parse_from_str(raw, "%Y-%m-%d")
+        .ok()
+        .and_then(|d| d.and_hms_opt(0, 0, 0))
+        .map(|d| d.and_utc())
+}
+
+/// Rewrites archived dates that were stored as the feed's raw string into RFC 3339 UTC, keeping
+/// the original in `pub_date_raw`. Unparseable dates fall back to when the item was archived.
+async fn normalize_pub_dates(tx: &mut Transaction<'_, Sqlite>) -> Result<(), sqlx::Error> {
+    let rows: Vec<(String, String, String)> = sqlx::query_as(
+        "SELECT id, pub_date, archived_at FROM items_archive WHERE pub_date_raw IS NULL",
+    )
+    .fetch_all(&mut **tx)
+    .await?;
+
+    for (id, pub_date, archived_at) in rows {
+        let normalized = match parse_pub_date(&pub_date) {
+            Some(date) => date.to_rfc3339(),
+            None => archived_at,
+        };
+        if normalized == pub_date {
+            continue;
+        }
+        sqlx::query("UPDATE items_archive SET pub_date = ?1, pub_date_raw = ?2 WHERE id = ?3")
+            .bind(normalized)
+            .bind(pub_date)
+            .bind(id)
+            .execute(&mut **tx)
+            .await?;
+    }
+    Ok(())
 }
 
 impl SeenStore {
     pub async fn new(db_path: &str) -> Result<Self, sqlx::Error> {
         let url = format!("sqlite://{}", db_path);
         let pool = SqlitePool::connect(&url).await?;
-        let store = SeenStore { pool };
+        let mut store = SeenStore { pool, fts: false };
         store.init().await?;
+        store.fts = match store.init_search().await {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Full-text search unavailable, falling back to LIKE: {}", e);
+                false
+            }
+        };
         Ok(store)
     }
 
+    /// Sets up the FTS5 index over archived items, kept in sync by triggers.
+    /// Not part of `MIGRATIONS` since it fails on SQLite builds without FTS5.
+    async fn init_search(&self) -> Result<(), sqlx::Error> {
+        let exists: Option<i64> = sqlx::query_scalar(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'items_search'",
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "CREATE VIRTUAL TABLE IF NOT EXISTS items_search USING fts5(id UNINDEXED, title, description, content)",
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            CREATE TRIGGER IF NOT EXISTS items_search_insert AFTER INSERT ON items_archive BEGIN
+                INSERT INTO items_search (id, title, description, content)
+                VALUES (new.id, new.title, new.description, new.content);
+            END
+            "#,
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            CREATE TRIGGER IF NOT EXISTS items_search_delete AFTER DELETE ON items_archive BEGIN
+                DELETE FROM items_search WHERE id = old.id;
+            END
+            "#,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        // Index whatever was archived before the search table existed
+        if exists.is_none() {
+            sqlx::query(
+                "INSERT INTO items_search (id, title, description, content) SELECT id, title, description, content FROM items_archive",
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await
+    }
+
     async fn init(&self) -> Result<(), sqlx::Error> {
         sqlx::query(
             r#"
@@ -60,11 +254,57 @@ impl SeenStore {
         .execute(&self.pool)
         .await?;
 
+        self.migrate().await
+    }
+
+    async fn migrate(&self) -> Result<(), sqlx::Error> {
+        let version: i64 = sqlx::query_scalar("PRAGMA user_version")
+            .fetch_one(&self.pool)
+            .await?;
+
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            let mut tx = self.pool.begin().await?;
+            if i + 1 == PUB_DATE_INDEX_VERSION {
+                normalize_pub_dates(&mut tx).await?;
+            }
+            sqlx::query(migration).execute(&mut *tx).await?;
+            sqlx::query(&format!("PRAGMA user_version = {}", i + 1))
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+        }
+
         Ok(())
     }
 
+    async fn size_bytes(&self) -> Result<i64, sqlx::Error> {
+        let pages: i64 = sqlx::query_scalar("PRAGMA page_count")
+            .fetch_one(&self.pool)
+            .await?;
+        let page_size: i64 = sqlx::query_scalar("PRAGMA page_size")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(pages * page_size)
+    }
+
+    /// Rebuilds the database file to give space freed by deleted rows back to the OS.
+    /// Holds an exclusive lock until done, so writers wait. Returns the size before and after.
+    pub async fn vacuum(&self) -> Result<(i64, i64), sqlx::Error> {
+        let before = self.size_bytes().await?;
+        info!("Vacuuming database, {} bytes before", before);
+        let start = std::time::Instant::now();
+        sqlx::query("VACUUM").execute(&self.pool).await?;
+        let after = self.size_bytes().await?;
+        info!(
+            "Vacuumed database in {:.2?}, {} bytes after",
+            start.elapsed(),
+            after
+        );
+        Ok((before, after))
+    }
+
     pub async fn get_feeds(&self) -> Vec<String> {
-        match sqlx::query_scalar::<_, String>("SELECT feed FROM feeds")
+        match sqlx::query_scalar::<_, String>("SELECT feed FROM feeds WHERE disabled = 0")
             .fetch_all(&self.pool)
             .await
         {
@@ -76,6 +316,213 @@ impl SeenStore {
         }
     }
 
+    /// `key=value` options stored with feeds added through `feed add`
+    pub async fn feed_options(&self) -> HashMap<String, Vec<(String, String)>> {
+        let res = sqlx::query_as::<_, (String, String)>(
+            "SELECT feed, options FROM feeds WHERE options IS NOT NULL",
+        )
+        .fetch_all(&self.pool)
+        .await;
+
+        match res {
+            Ok(rows) => rows
+                .into_iter()
+                .filter_map(|(feed, options)| match serde_json::from_str(&options) {
+                    Ok(options) => Some((feed, options)),
+                    Err(e) => {
+                        warn!(
+                            "Ignoring unreadable options of feed {}: {}",
+                            redact_url(&feed),
+                            e
+                        );
+                        None
+                    }
+                })
+                .collect(),
+            Err(e) => {
+                error!("SeenStore::feed_options error: {}", e);
+                HashMap::new()
+            }
+        }
+    }
+
+    /// Titles and last successful refreshes of stored feeds
+    pub async fn feed_records(&self) -> HashMap<String, FeedRecord> {
+        let res = sqlx::query_as::<_, (String, Option<String>, Option<String>)>(
+            "SELECT feed, title, last_success FROM feeds",
+        )
+        .fetch_all(&self.pool)
+        .await;
+
+        match res {
+            Ok(rows) => rows
+                .into_iter()
+                .map(|(feed, title, last_success)| {
+                    let last_success = last_success
+                        .and_then(|t| DateTime::parse_from_rfc3339(&t).ok())
+                        .map(|t| t.with_timezone(&Utc));
+                    (
+                        feed,
+                        FeedRecord {
+                            title,
+                            last_success,
+                        },
+                    )
+                })
+                .collect(),
+            Err(e) => {
+                error!("SeenStore::feed_records error: {}", e);
+                HashMap::new()
+            }
+        }
+    }
+
+    /// Remembers a successful refresh and the channel title it returned
+    pub async fn record_feed_success(&self, feed: &str, title: Option<&str>) -> bool {
+        let res = sqlx::query(
+            "UPDATE feeds SET last_success = ?1, title = COALESCE(?2, title) WHERE feed = ?3",
+        )
+        .bind(Utc::now().to_rfc3339())
+        .bind(title)
+        .bind(feed)
+        .execute(&self.pool)
+        .await;
+
+        match res {
+            Ok(done) => done.rows_affected() > 0,
+            Err(e) => {
+                error!(
+                    "SeenStore::record_feed_success error for feed={}: {}",
+                    redact_url(feed),
+                    e
+                );
+                false
+            }
+        }
+    }
+
+    /// Stores the options a feed was added with so they are applied again after a restart
+    pub async fn set_feed_options(&self, feed: &str, options: &[(String, String)]) -> bool {
+        let options = serde_json::to_string(options).expect("string pairs always serialize");
+        let res = sqlx::query("UPDATE feeds SET options = ?1 WHERE feed = ?2")
+            .bind(options)
+            .bind(canonical_url(feed))
+            .execute(&self.pool)
+            .await;
+
+        match res {
+            Ok(done) => done.rows_affected() > 0,
+            Err(e) => {
+                error!("SeenStore::set_feed_options error: {}", e);
+                false
+            }
+        }
+    }
+
+    pub async fn get_disabled_feeds(&self) -> Vec<String> {
+        match sqlx::query_scalar::<_, String>("SELECT feed FROM feeds WHERE disabled = 1")
+            .fetch_all(&self.pool)
+            .await
+        {
+            Ok(list) => list,
+            Err(e) => {
+                error!("SeenStore::get_disabled_feeds error: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    pub async fn set_feed_disabled(&self, feed: &str, disabled: bool) -> bool {
+        let res = sqlx::query("UPDATE feeds SET disabled = ?1 WHERE feed = ?2")
+            .bind(disabled)
+            .bind(canonical_url(feed))
+            .execute(&self.pool)
+            .await;
+
+        match res {
+            Ok(done) => done.rows_affected() > 0,
+            Err(e) => {
+                error!(
+                    "SeenStore::set_feed_disabled error for feed={}: {}",
+                    redact_url(feed),
+                    e
+                );
+                false
+            }
+        }
+    }
+
+    pub async fn get_paused_feeds(&self) -> Vec<String> {
+        match sqlx::query_scalar::<_, String>("SELECT feed FROM feeds WHERE paused = 1")
+            .fetch_all(&self.pool)
+            .await
+        {
+            Ok(list) => list,
+            Err(e) => {
+                error!("SeenStore::get_paused_feeds error: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    pub async fn set_feed_paused(&self, feed: &str, paused: bool) -> bool {
+        let res = sqlx::query("UPDATE feeds SET paused = ?1 WHERE feed = ?2")
+            .bind(paused)
+            .bind(canonical_url(feed))
+            .execute(&self.pool)
+            .await;
+
+        match res {
+            Ok(done) => done.rows_affected() > 0,
+            Err(e) => {
+                error!(
+                    "SeenStore::set_feed_paused error for feed={}: {}",
+                    redact_url(feed),
+                    e
+                );
+                false
+            }
+        }
+    }
+
+    /// Moves a feed's row to `to`. When `to` is already stored its row, with its options and
+    /// flags, is kept and the row of `from` is dropped instead of overwriting it.
+    pub async fn rename_feed(&self, from: &str, to: &str) -> bool {
+        match self.try_rename_feed(from, &canonical_url(to)).await {
+            Ok(renamed) => renamed,
+            Err(e) => {
+                error!(
+                    "SeenStore::rename_feed error for feed={}: {}",
+                    redact_url(from),
+                    e
+                );
+                false
+            }
+        }
+    }
+
+    async fn try_rename_feed(&self, from: &str, to: &str) -> Result<bool, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let exists = sqlx::query_scalar::<_, i64>("SELECT 1 FROM feeds WHERE feed = ?1")
+            .bind(to)
+            .fetch_optional(&mut *tx)
+            .await?
+            .is_some();
+        let statement = if exists {
+            "DELETE FROM feeds WHERE feed = ?1"
+        } else {
+            "UPDATE feeds SET feed = ?2 WHERE feed = ?1"
+        };
+        let changed = sqlx::query(statement)
+            .bind(from)
+            .bind(to)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        tx.commit().await?;
+        Ok(changed > 0)
+    }
+
     pub async fn push_feeds(&self, feeds: Vec<String>) -> u64 {
         let mut inserted = 0;
 
@@ -87,7 +534,8 @@ impl SeenStore {
             ON CONFLICT(feed) DO NOTHING
             "#,
             )
-            .bind(feed)
+            // Keeps the conflict check meaningful for URLs that differ only in form
+            .bind(canonical_url(&feed))
             .execute(&self.pool)
             .await;
 
@@ -112,7 +560,7 @@ impl SeenStore {
             WHERE feed = ?1
             "#,
             )
-            .bind(feed)
+            .bind(canonical_url(&feed))
             .execute(&self.pool)
             .await;
 
@@ -127,6 +575,167 @@ impl SeenStore {
         removed
     }
 
+    /// Number of items ever archived per feed source and when the newest one was archived
+    pub async fn item_stats(&self) -> HashMap<String, FeedItemStats> {
+        let res = sqlx::query_as::<_, (String, i64, String)>(
+            "SELECT feed_source, COUNT(*), MAX(archived_at) FROM items_archive GROUP BY feed_source",
+        )
+        .fetch_all(&self.pool)
+        .await;
+
+        match res {
+            Ok(rows) => rows
+                .into_iter()
+                .map(|(feed, items, latest)| (feed, FeedItemStats { items, latest }))
+                .collect(),
+            Err(e) => {
+                error!("SeenStore::item_stats error: {}", e);
+                HashMap::new()
+            }
+        }
+    }
+
+    /// Publication date of each feed source's newest archived item. Undated items count with the
+    /// time they were archived.
+    pub async fn newest_items(&self) -> HashMap<String, DateTime<Utc>> {
+        let res = sqlx::query_as::<_, (String, String)>(
+            "SELECT feed_source, MAX(pub_date) FROM items_archive GROUP BY feed_source",
+        )
+        .fetch_all(&self.pool)
+        .await;
+
+        match res {
+            Ok(rows) => rows
+                .into_iter()
+                .filter_map(|(feed, date)| {
+                    let date = DateTime::parse_from_rfc3339(&date).ok()?;
+                    Some((feed, date.with_timezone(&Utc)))
+                })
+                .collect(),
+            Err(e) => {
+                error!("SeenStore::newest_items error: {}", e);
+                HashMap::new()
+            }
+        }
+    }
+
+    /// Clears what is known about a feed's items so they are notified again. Also drops the
+    /// global dedup claims on their links. Returns the removed seen ids and archived items.
+    pub async fn forget_feed(
+        &self,
+        feed: &str,
+        keep_archive: bool,
+    ) -> Result<(u64, u64), sqlx::Error> {
+        let links: Vec<Option<String>> =
+            sqlx::query_scalar("SELECT link FROM items_archive WHERE feed_source = ?1")
+                .bind(feed)
+                .fetch_all(&self.pool)
+                .await?;
+
+        let mut tx = self.pool.begin().await?;
+        let mut seen = sqlx::query(
+            "DELETE FROM seen_ids WHERE id IN (SELECT id FROM items_archive WHERE feed_source = ?1)",
+        )
+        .bind(feed)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+
+        for link in links.iter().flatten().filter_map(|l| normalize_link(l)) {
+            seen += sqlx::query("DELETE FROM seen_ids WHERE id = ?1")
+                .bind(format!("link:{}", link))
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+        }
+
+        let archived = if keep_archive {
+            0
+        } else {
+            sqlx::query("DELETE FROM items_archive WHERE feed_source = ?1")
+                .bind(feed)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected()
+        };
+        tx.commit().await?;
+
+        Ok((seen, archived))
+    }
+
+    /// Archived items whose title, description or content match `query`. The FTS5 index
+    /// requires every word to appear, the LIKE fallback matches the query as one phrase.
+    pub async fn search(&self, query: &str, limit: u32) -> Vec<ArchivedItem> {
+        let res = if self.fts {
+            // Quoted so user input is matched as plain terms instead of FTS5 query syntax
+            let terms: Vec<String> = query
+                .split_whitespace()
+                .map(|t| format!("\"{}\"", t.replace('"', "\"\"")))
+                .collect();
+            sqlx::query_as::<_, ArchivedItem>(
+                r#"
+                SELECT a.title, a.link, a.pub_date, a.feed_source, a.enclosure_url, a.enclosure_type, a.enclosure_length
+                FROM items_search s
+                JOIN items_archive a ON a.id = s.id
+                WHERE items_search MATCH ?1
+                ORDER BY s.rank
+                LIMIT ?2
+                "#,
+            )
+            .bind(terms.join(" "))
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await
+        } else {
+            sqlx::query_as::<_, ArchivedItem>(
+                r#"
+                SELECT title, link, pub_date, feed_source, enclosure_url, enclosure_type, enclosure_length
+                FROM items_archive
+                WHERE title LIKE ?1 OR description LIKE ?1 OR content LIKE ?1
+                ORDER BY archived_at DESC
+                LIMIT ?2
+                "#,
+            )
+            .bind(format!("%{}%", query.trim()))
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await
+        };
+
+        match res {
+            Ok(items) => items,
+            Err(e) => {
+                error!("SeenStore::search error for query={}: {}", query, e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Most recently archived items, optionally limited to one feed
+    pub async fn recent_items(&self, feed: Option<&str>, limit: u32) -> Vec<ArchivedItem> {
+        let res = sqlx::query_as::<_, ArchivedItem>(
+            r#"
+            SELECT title, link, pub_date, feed_source, enclosure_url, enclosure_type, enclosure_length
+            FROM items_archive
+            WHERE ?1 IS NULL OR feed_source = ?1
+            ORDER BY archived_at DESC
+            LIMIT ?2
+            "#,
+        )
+        .bind(feed)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await;
+
+        match res {
+            Ok(items) => items,
+            Err(e) => {
+                error!("SeenStore::recent_items error: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
     pub async fn is_seen(&self, id: &str) -> bool {
         let res = sqlx::query("SELECT 1 FROM seen_ids WHERE id = ?1 LIMIT 1")
             .bind(id)
@@ -142,7 +751,94 @@ impl SeenStore {
             }
         }
     }
-    pub async fn mark_seen(&self, item: &Item, id: &str, feed_source: &str) -> bool {
+
+    /// Marks an id as seen without archiving anything. Returns false if it was already seen.
+    pub async fn claim_id(&self, id: &str) -> bool {
+        let res = sqlx::query(
+            r#"
+            INSERT INTO seen_ids (id, first_seen)
+            VALUES (?1, ?2)
+            ON CONFLICT(id) DO NOTHING
+            "#,
+        )
+        .bind(id)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&self.pool)
+        .await;
+
+        match res {
+            Ok(done) => done.rows_affected() == 1,
+            Err(e) => {
+                error!("SeenStore::claim_id error for id={}: {}", id, e);
+                true
+            }
+        }
+    }
+
+    /// Items still waiting for delivery, oldest first
+    pub async fn pending_items(&self) -> Vec<PendingItem> {
+        let res = sqlx::query_as::<_, PendingItem>(
+            r#"
+            SELECT id, title, link, description, pub_date_raw, feed_source
+            FROM items_archive
+            WHERE delivered = 0
+            ORDER BY archived_at ASC
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await;
+
+        match res {
+            Ok(items) => items,
+            Err(e) => {
+                error!("SeenStore::pending_items error: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Looks up an archived item by its id, guid or link
+    pub async fn find_item(&self, key: &str) -> Option<PendingItem> {
+        let res = sqlx::query_as::<_, PendingItem>(
+            r#"
+            SELECT id, title, link, description, pub_date_raw, feed_source
+            FROM items_archive
+            WHERE id = ?1 OR guid = ?1 OR link = ?1
+            ORDER BY archived_at DESC
+            LIMIT 1
+            "#,
+        )
+        .bind(key)
+        .fetch_optional(&self.pool)
+        .await;
+
+        match res {
+            Ok(item) => item,
+            Err(e) => {
+                error!("SeenStore::find_item error for key={}: {}", key, e);
+                None
+            }
+        }
+    }
+
+    pub async fn mark_delivered(&self, id: &str) -> bool {
+        let res = sqlx::query("UPDATE items_archive SET delivered = 1 WHERE id = ?1")
+            .bind(id)
+            .execute(&self.pool)
+            .await;
+
+        match res {
+            Ok(done) => done.rows_affected() > 0,
+            Err(e) => {
+                error!("SeenStore::mark_delivered error for id={}: {}", id, e);
+                false
+            }
+        }
+    }
+
+    /// Archives an item and marks it seen in one transaction. `pending` items stay undelivered
+    /// until `mark_delivered` so they can be re-emitted after a crash.
+    pub async fn mark_seen(&self, item: &Item, id: &str, feed_source: &str, pending: bool) -> bool {
         let title = item.title().map(|s| s.to_owned());
         let link = item.link().map(|s| s.to_owned());
         let description = item.description().map(|s| s.to_owned());
@@ -171,10 +867,16 @@ impl SeenStore {
 
         let guid_str = item.guid().map(|g: &Guid| g.value().to_owned());
 
-        let pub_date = match item.pub_date() {
-            Some(d) => d.to_owned(),
-            None => Utc::now().to_rfc3339(),
-        };
+        let now = Utc::now().to_rfc3339();
+
+        // Stored as RFC 3339 UTC so it sorts, the feed's original string is kept alongside.
+        // Missing or unparseable dates fall back to the archive time.
+        let pub_date_raw = item.pub_date().map(|d| d.to_owned());
+        let pub_date = item
+            .pub_date()
+            .and_then(parse_pub_date)
+            .map(|d| d.to_rfc3339())
+            .unwrap_or_else(|| now.clone());
 
         let (source_title, source_url) = match item.source() {
             Some(src) => {
@@ -186,7 +888,15 @@ impl SeenStore {
         };
 
         let content = item.content().map(|s| s.to_owned());
-        let now = Utc::now().to_rfc3339();
+
+        let (enclosure_url, enclosure_type, enclosure_length) = match item.enclosure() {
+            Some(enc) => (
+                Some(enc.url().to_owned()),
+                Some(enc.mime_type().to_owned()),
+                enc.length().trim().parse::<i64>().ok(),
+            ),
+            None => (None, None, None),
+        };
 
         let mut tx = match self.pool.begin().await {
             Ok(tx) => tx,
@@ -235,9 +945,14 @@ impl SeenStore {
                 source_url,
                 content,
                 feed_source,
-                archived_at
+                archived_at,
+                enclosure_url,
+                enclosure_type,
+                enclosure_length,
+                pub_date_raw,
+                delivered
             )
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18)
             ON CONFLICT(id) DO NOTHING
             "#,
         )
@@ -254,6 +969,11 @@ impl SeenStore {
         .bind(content)
         .bind(feed_source)
         .bind(now)
+        .bind(enclosure_url)
+        .bind(enclosure_type)
+        .bind(enclosure_length)
+        .bind(pub_date_raw)
+        .bind(!pending)
         .execute(&mut *tx)
         .await;
 
@@ -274,3 +994,340 @@ impl SeenStore {
         rows_affected == 1
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fresh database file in the temp dir, removed again by the caller through `cleanup`
+    async fn scratch_store(name: &str) -> (SeenStore, std::path::PathBuf) {
+        let path =
+            std::env::temp_dir().join(format!("korvatunturi-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        std::fs::File::create(&path).unwrap();
+        let store = SeenStore::new(path.to_str().unwrap()).await.unwrap();
+        (store, path)
+    }
+
+    async fn cleanup(store: SeenStore, path: std::path::PathBuf) {
+        store.pool.close().await;
+        let _ = std::fs::remove_file(path);
+    }
+
+    /// Archives an already delivered item with the given id as guid
+    async fn archive(store: &SeenStore, feed: &str, id: &str, title: &str, description: &str) {
+        let mut item = Item::default();
+        item.set_title(title.to_string());
+        item.set_description(description.to_string());
+        item.set_link(format!("{}/{}", feed, id));
+        item.set_guid(Guid {
+            value: id.to_string(),
+            permalink: false,
+        });
+        assert!(store.mark_seen(&item, id, feed, false).await);
+    }
+
+    #[tokio::test]
+    async fn rename_moves_the_row_and_its_options() {
+        let (store, path) = scratch_store("rename").await;
+        store
+            .push_feeds(vec!["https://old.example/rss".into()])
+            .await;
+        let options = vec![("interval".to_string(), "60".to_string())];
+        store
+            .set_feed_options("https://old.example/rss", &options)
+            .await;
+
+        assert!(
+            store
+                .rename_feed("https://old.example/rss", "https://new.example/rss")
+                .await
+        );
+        assert_eq!(store.get_feeds().await, vec!["https://new.example/rss"]);
+        assert_eq!(
+            store.feed_options().await["https://new.example/rss"],
+            options
+        );
+        cleanup(store, path).await;
+    }
+
+    #[tokio::test]
+    async fn rename_onto_a_stored_feed_keeps_its_row() {
+        let (store, path) = scratch_store("rename-merge").await;
+        store
+            .push_feeds(vec![
+                "https://old.example/rss".into(),
+                "https://new.example/rss".into(),
+            ])
+            .await;
+        let options = vec![("interval".to_string(), "60".to_string())];
+        store
+            .set_feed_options("https://new.example/rss", &options)
+            .await;
+        store.set_feed_paused("https://new.example/rss", true).await;
+
+        assert!(
+            store
+                .rename_feed("https://old.example/rss", "https://new.example/rss")
+                .await
+        );
+        assert_eq!(store.get_feeds().await, vec!["https://new.example/rss"]);
+        assert_eq!(
+            store.feed_options().await["https://new.example/rss"],
+            options
+        );
+        assert_eq!(
+            store.get_paused_feeds().await,
+            vec!["https://new.example/rss"]
+        );
+        cleanup(store, path).await;
+    }
+
+    #[test]
+    fn parses_the_pub_date_formats_seen_in_feeds() {
+        let expected = "2024-03-05T14:30:00+00:00";
+        for raw in [
+            "Tue, 05 Mar 2024 14:30:00 GMT",
+            "Tue, 05 Mar 2024 16:30:00 +0200",
+            // Spelled out and wrong weekdays
+            "Tuesday, 05 Mar 2024 14:30:00 GMT",
+            "Fri, 05 Mar 2024 14:30:00 GMT",
+            "2024-03-05T14:30:00Z",
+            "2024-03-05T15:30:00+01:00",
+            "2024-03-05 15:30:00+01:00",
+            "2024-03-05 15:30:00 +0100",
+            // No time zone is taken as UTC
+            "2024-03-05T14:30:00",
+            "2024-03-05 14:30:00.000",
+            "  Tue, 05 Mar 2024 14:30:00 GMT  ",
+        ] {
+            assert_eq!(
+                parse_pub_date(raw).map(|d| d.to_rfc3339()).as_deref(),
+                Some(expected),
+                "{}",
+                raw
+            );
+        }
+        assert_eq!(
+            parse_pub_date("2024-03-05")
+                .map(|d| d.to_rfc3339())
+                .as_deref(),
+            Some("2024-03-05T00:00:00+00:00")
+        );
+        assert_eq!(parse_pub_date("yesterday"), None);
+        assert_eq!(parse_pub_date(""), None);
+    }
+
+    #[tokio::test]
+    async fn upgrades_a_database_from_before_the_migrations() {
+        let path =
+            std::env::temp_dir().join(format!("korvatunturi-upgrade-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        std::fs::File::create(&path).unwrap();
+
+        // Base tables as the first release created them, with user_version still 0
+        let old = SqlitePool::connect(&format!("sqlite://{}", path.display()))
+            .await
+            .unwrap();
+        for statement in [
+            "CREATE TABLE seen_ids (id TEXT PRIMARY KEY, first_seen TEXT NOT NULL)",
+            "CREATE TABLE items_archive (id TEXT PRIMARY KEY, title TEXT, link TEXT, description TEXT, author TEXT, categories TEXT, guid TEXT, pub_date TEXT NOT NULL, source_title TEXT, source_url TEXT, content TEXT, feed_source TEXT NOT NULL, archived_at TEXT NOT NULL)",
+            "CREATE TABLE feeds (feed TEXT PRIMARY KEY)",
+            "INSERT INTO feeds (feed) VALUES ('https://example.com/rss')",
+            "INSERT INTO items_archive (id, title, pub_date, feed_source, archived_at) VALUES ('a', 'Dated', 'Tue, 05 Mar 2024 14:30:00 GMT', 'https://example.com/rss', '2024-03-06T00:00:00+00:00')",
+            "INSERT INTO items_archive (id, title, pub_date, feed_source, archived_at) VALUES ('b', 'Undated', 'someday', 'https://example.com/rss', '2024-03-06T00:00:00+00:00')",
+        ] {
+            sqlx::query(statement).execute(&old).await.unwrap();
+        }
+        old.close().await;
+
+        let store = SeenStore::new(path.to_str().unwrap()).await.unwrap();
+        let version: i64 = sqlx::query_scalar("PRAGMA user_version")
+            .fetch_one(&store.pool)
+            .await
+            .unwrap();
+        assert_eq!(version as usize, MIGRATIONS.len());
+        assert_eq!(store.get_feeds().await, vec!["https://example.com/rss"]);
+
+        let rows: Vec<(String, String, Option<String>, i64)> = sqlx::query_as(
+            "SELECT id, pub_date, pub_date_raw, delivered FROM items_archive ORDER BY id",
+        )
+        .fetch_all(&store.pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                (
+                    "a".to_string(),
+                    "2024-03-05T14:30:00+00:00".to_string(),
+                    Some("Tue, 05 Mar 2024 14:30:00 GMT".to_string()),
+                    1
+                ),
+                (
+                    "b".to_string(),
+                    "2024-03-06T00:00:00+00:00".to_string(),
+                    Some("someday".to_string()),
+                    1
+                ),
+            ]
+        );
+        cleanup(store, path).await;
+    }
+
+    #[tokio::test]
+    async fn feeds_match_whatever_form_their_url_is_given_in() {
+        let (store, path) = scratch_store("url-forms").await;
+        store
+            .push_feeds(vec!["HTTPS://Example.com/rss/".into()])
+            .await;
+        assert_eq!(store.get_feeds().await, vec!["https://example.com/rss"]);
+        assert_eq!(
+            store
+                .push_feeds(vec!["https://example.com/rss".into()])
+                .await,
+            0
+        );
+
+        assert!(store.set_feed_paused("https://EXAMPLE.com/rss", true).await);
+        assert_eq!(
+            store.get_paused_feeds().await,
+            vec!["https://example.com/rss"]
+        );
+        assert!(
+            store
+                .set_feed_disabled("https://example.com:443/rss/", true)
+                .await
+        );
+        assert_eq!(
+            store.get_disabled_feeds().await,
+            vec!["https://example.com/rss"]
+        );
+
+        assert_eq!(
+            store
+                .remove_feeds(vec!["https://example.com/rss/#top".into()])
+                .await,
+            1
+        );
+        assert!(store.get_disabled_feeds().await.is_empty());
+        cleanup(store, path).await;
+    }
+
+    #[tokio::test]
+    async fn item_stats_count_each_feed_separately() {
+        let (store, path) = scratch_store("item-stats").await;
+        for id in ["a-1", "a-2", "a-3"] {
+            archive(&store, "https://a.example/rss", id, "A", "").await;
+        }
+        archive(&store, "https://b.example/rss", "b-1", "B", "").await;
+
+        let stats = store.item_stats().await;
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats["https://a.example/rss"].items, 3);
+        assert_eq!(stats["https://b.example/rss"].items, 1);
+        assert!(DateTime::parse_from_rfc3339(&stats["https://b.example/rss"].latest).is_ok());
+        cleanup(store, path).await;
+    }
+
+    #[tokio::test]
+    async fn vacuum_gives_back_space_of_deleted_rows() {
+        let (store, path) = scratch_store("vacuum").await;
+        let padding = "x".repeat(4096);
+        for n in 0..50 {
+            archive(
+                &store,
+                "https://a.example/rss",
+                &n.to_string(),
+                "A",
+                &padding,
+            )
+            .await;
+        }
+        store
+            .forget_feed("https://a.example/rss", false)
+            .await
+            .unwrap();
+
+        let (before, after) = store.vacuum().await.unwrap();
+        assert!(after < before, "{} -> {}", before, after);
+        cleanup(store, path).await;
+    }
+
+    #[tokio::test]
+    async fn search_finds_archived_items_with_and_without_fts() {
+        let (mut store, path) = scratch_store("search").await;
+        assert!(store.fts);
+        archive(
+            &store,
+            "https://a.example/rss",
+            "1",
+            "Rust async runtimes compared",
+            "",
+        )
+        .await;
+        archive(
+            &store,
+            "https://a.example/rss",
+            "2",
+            "Shell tips",
+            r#"Why "quoted" args matter, part-2"#,
+        )
+        .await;
+
+        for fts in [true, false] {
+            store.fts = fts;
+            let titles = |items: Vec<ArchivedItem>| -> Vec<String> {
+                items.into_iter().filter_map(|item| item.title).collect()
+            };
+            assert_eq!(
+                titles(store.search("runtimes", 10).await),
+                ["Rust async runtimes compared"],
+                "fts={}",
+                fts
+            );
+            // FTS5 syntax in the query is taken literally instead of failing the search
+            for query in [r#""quoted""#, "part-2"] {
+                assert_eq!(
+                    titles(store.search(query, 10).await),
+                    ["Shell tips"],
+                    "fts={} query={}",
+                    fts,
+                    query
+                );
+            }
+            assert!(store.search(r#"args" OR "tips"#, 10).await.is_empty());
+            assert!(store.search("missing", 10).await.is_empty());
+        }
+
+        // Only the index matches words apart from each other, LIKE wants the phrase
+        store.fts = true;
+        assert_eq!(store.search("compared rust", 10).await.len(), 1);
+        store.fts = false;
+        assert!(store.search("compared rust", 10).await.is_empty());
+        cleanup(store, path).await;
+    }
+
+    #[tokio::test]
+    async fn feed_success_is_recorded_for_the_next_run() {
+        let (store, path) = scratch_store("feed-records").await;
+        let feed = "https://example.com/rss";
+        assert!(!store.record_feed_success(feed, Some("Example Feed")).await);
+        store.push_feeds(vec![feed.into()]).await;
+        assert!(store.feed_records().await[feed].last_success.is_none());
+
+        let before = Utc::now();
+        assert!(store.record_feed_success(feed, Some("Example Feed")).await);
+        let record = store.feed_records().await.remove(feed).unwrap();
+        assert_eq!(record.title.as_deref(), Some("Example Feed"));
+        let first = record.last_success.unwrap();
+        assert!(first >= before - chrono::TimeDelta::seconds(1));
+
+        // A channel without a title keeps the one it had
+        assert!(store.record_feed_success(feed, None).await);
+        let record = store.feed_records().await.remove(feed).unwrap();
+        assert_eq!(record.title.as_deref(), Some("Example Feed"));
+        assert!(record.last_success.unwrap() >= first);
+        cleanup(store, path).await;
+    }
+}
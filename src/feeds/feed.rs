@@ -1,105 +1,1457 @@
+use chrono::{TimeDelta, Utc};
+use encoding_rs::{Encoding, UTF_8};
+use futures::future::BoxFuture;
+use log::{debug, trace, warn};
+use reqwest::{
+    Client, NoProxy, Proxy, Response, StatusCode, Url, header::CACHE_CONTROL, redirect::Policy,
+};
 use rss::{Channel, Item};
 use sha2::{Digest, Sha256};
-use std::collections::{HashSet, VecDeque};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
 
-use crate::db::SeenStore;
+use tokio::sync::{Semaphore, mpsc::Sender};
+
+use crate::{
+    config::{FeedSpec, HashFallback, canonical_url, redact_url},
+    db::{SeenStore, parse_pub_date},
+    feeds::watcher::{FeedEvent, send_event},
+};
+
+/// Shared by every feed and webhook so connections are pooled. Compressed responses are decoded
+/// transparently.
+static HTTP_CLIENT: OnceLock<Client> = OnceLock::new();
+
+/// Options for the shared HTTP client
+pub struct HttpSettings {
+    /// Redirects followed per request, 0 disables following them
+    pub max_redirects: usize,
+    /// http(s) or socks5 proxy for every request. Without it the standard proxy env vars apply.
+    pub proxy: Option<String>,
+    /// Comma separated hosts and networks reached without `proxy`
+    pub no_proxy: Option<String>,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        HttpSettings {
+            max_redirects: 10,
+            proxy: None,
+            no_proxy: None,
+        }
+    }
+}
+
+/// Builds the shared HTTP client. Has no effect once a feed has been fetched.
+pub fn configure_http(settings: &HttpSettings) -> Result<(), reqwest::Error> {
+    let client = build_client(settings)?;
+    let _ = HTTP_CLIENT.set(client);
+    Ok(())
+}
+
+fn build_client(settings: &HttpSettings) -> Result<Client, reqwest::Error> {
+    let redirects = match settings.max_redirects {
+        0 => Policy::none(),
+        n => Policy::limited(n),
+    };
+    let mut builder = Client::builder()
+        .gzip(true)
+        .deflate(true)
+        .brotli(true)
+        .redirect(redirects);
+    if let Some(proxy) = &settings.proxy {
+        let no_proxy = settings.no_proxy.as_deref().and_then(NoProxy::from_string);
+        builder = builder.proxy(Proxy::all(proxy)?.no_proxy(no_proxy));
+    }
+    builder.build()
+}
+
+pub fn http_client() -> &'static Client {
+    HTTP_CLIENT.get_or_init(|| {
+        build_client(&HttpSettings::default()).expect("Failed to build HTTP client")
+    })
+}
+
+/// Where feed bodies come from. Production uses `HttpFetcher`, tests can hand `RssFeed` canned
+/// fixtures instead of going to the network.
+pub trait FeedFetcher: Send + Sync {
+    fn load<'a>(
+        &'a self,
+        spec: &'a FeedSpec,
+        max_body: usize,
+    ) -> BoxFuture<'a, Result<Loaded, Box<dyn std::error::Error + Send + Sync>>>;
+}
+
+/// Fetches feeds over http(s) with the shared client
+pub struct HttpFetcher(Client);
+
+impl FeedFetcher for HttpFetcher {
+    fn load<'a>(
+        &'a self,
+        spec: &'a FeedSpec,
+        max_body: usize,
+    ) -> BoxFuture<'a, Result<Loaded, Box<dyn std::error::Error + Send + Sync>>> {
+        Box::pin(load(&self.0, spec, max_body))
+    }
+}
 
-#[derive(Clone)]
 pub struct RssFeed {
     source: String,
-    seen_items: HashSet<String>,
-    seen_order: VecDeque<String>,
-    items: Vec<Item>,
+    spec: FeedSpec,
+    fetcher: Arc<dyn FeedFetcher>,
+    /// Cached ids and the generation they were last seen in, higher is more recent
+    seen_items: HashMap<String, u64>,
+    /// Ids in the order they were seen. Entries of ids seen again since are stale and skipped
+    /// when evicting.
+    seen_order: VecDeque<(u64, String)>,
+    generation: u64,
+    /// New items of the last buffered `refresh`, drained by `items()`
+    items: Vec<(String, Item)>,
     max_cache: usize,
+    /// Largest accepted response body in bytes, 0 means unlimited
+    max_body: usize,
+    /// Paused feeds keep archiving new items but never notify them
+    paused: Arc<AtomicBool>,
+    /// Final URL of the last fetch when redirects led away from `source`
+    moved_to: Option<String>,
+    /// The larger of the channel's `<ttl>` and the response's max-age on the last fetch
+    advertised_ttl: Option<Duration>,
+    /// Channel `<title>` of the last fetch
+    title: Option<String>,
+    /// Channel `<lastBuildDate>` of the last fetch whose items were processed
+    last_build_date: Option<String>,
+    /// Shared cap on feeds downloading at once
+    fetch_limit: Option<Arc<Semaphore>>,
+    /// Shared spacing of requests to the same host
+    host_limit: Option<Arc<HostLimiter>>,
+    /// Items are only archived, never notified
+    archive_only: bool,
+    notification_limit: Option<NotificationBucket>,
+    /// Items archived without a notification since the last `take_suppressed`
+    suppressed: usize,
+}
+
+/// Spaces requests to the same host by at least `interval`, however many feeds live there.
+/// Shared by every refresh loop.
+pub struct HostLimiter {
+    interval: Duration,
+    /// Earliest time the next request to each host may go out
+    next: Mutex<HashMap<String, tokio::time::Instant>>,
+}
+
+impl HostLimiter {
+    /// A zero `interval` never waits
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Waits until a request to `url`'s host may go out. The slot is reserved before waiting, so
+    /// callers queue up one `interval` apart.
+    pub async fn wait(&self, url: &str) {
+        if self.interval.is_zero() {
+            return;
+        }
+        let Some(host) = Url::parse(url)
+            .ok()
+            .and_then(|u| u.host_str().map(str::to_string))
+        else {
+            return;
+        };
+        let slot = {
+            let mut next = self.next.lock().unwrap();
+            let now = tokio::time::Instant::now();
+            let slot = next.get(&host).copied().filter(|t| *t > now).unwrap_or(now);
+            next.insert(host, slot + self.interval);
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+}
+
+/// Token bucket holding up to an hour's worth of notifications, refilled continuously
+struct NotificationBucket {
+    per_hour: u32,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl NotificationBucket {
+    fn new(per_hour: u32) -> Self {
+        Self {
+            per_hour,
+            tokens: f64::from(per_hour),
+            refilled: Instant::now(),
+        }
+    }
+
+    fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let refill =
+            now.duration_since(self.refilled).as_secs_f64() * f64::from(self.per_hour) / 3600.0;
+        self.tokens = (self.tokens + refill).min(f64::from(self.per_hour));
+        self.refilled = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// Returns a token taken for a notification that was not sent after all
+    fn refund(&mut self) {
+        self.tokens = (self.tokens + 1.0).min(f64::from(self.per_hour));
+    }
 }
 
 impl RssFeed {
     pub async fn new(
-        url: String,
+        spec: FeedSpec,
         max_size: usize,
+        max_body: usize,
     ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
-        let content = reqwest::get(&url).await?.bytes().await?;
-        let mut channel = Channel::read_from(&content[..])?;
-        channel.set_link(&url);
+        let fetcher = Arc::new(HttpFetcher(http_client().clone()));
+        Self::with_fetcher(spec, max_size, max_body, fetcher).await
+    }
 
-        Ok(Self {
-            source: url,
-            seen_items: HashSet::new(),
+    /// Loads the feed through `fetcher`, which also serves every later refresh
+    pub async fn with_fetcher(
+        spec: FeedSpec,
+        max_size: usize,
+        max_body: usize,
+        fetcher: Arc<dyn FeedFetcher>,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let loaded = fetcher.load(&spec, max_body).await?;
+        let mut channel = Channel::read_from(&loaded.body[..])?;
+        channel.set_link(&spec.url);
+
+        let max_cache = spec.cache_size.unwrap_or(max_size);
+        let mut feed = Self {
+            source: canonical_url(&spec.url),
+            spec,
+            fetcher,
+            seen_items: HashMap::new(),
             seen_order: VecDeque::new(),
+            generation: 0,
             items: Vec::new(),
-            max_cache: max_size,
-        })
+            max_cache,
+            max_body,
+            paused: Arc::new(AtomicBool::new(false)),
+            moved_to: None,
+            advertised_ttl: None,
+            title: None,
+            last_build_date: None,
+            fetch_limit: None,
+            host_limit: None,
+            archive_only: false,
+            notification_limit: None,
+            suppressed: 0,
+        };
+        feed.note_fetch(&loaded, &channel);
+        Ok(feed)
+    }
+
+    /// Makes every later fetch wait for its turn at the feed's host
+    pub fn space_requests(&mut self, limit: Arc<HostLimiter>) {
+        self.host_limit = Some(limit);
+    }
+
+    /// Archives new items as already delivered, no events are emitted for them
+    pub fn archive_only(&mut self) {
+        self.archive_only = true;
+    }
+
+    /// Caps notifications at the feed's own `max_notifications_per_hour`, or `default` without
+    /// one. 0 leaves them unlimited.
+    pub fn limit_notifications(&mut self, default: u32) {
+        let per_hour = self.spec.max_notifications_per_hour.unwrap_or(default);
+        self.notification_limit = (per_hour > 0).then(|| NotificationBucket::new(per_hour));
+    }
+
+    /// Number of items archived without a notification because of the rate limit, since the
+    /// last call
+    pub fn take_suppressed(&mut self) -> usize {
+        std::mem::take(&mut self.suppressed)
+    }
+
+    /// Makes every later fetch wait for a permit of `limit` before downloading
+    pub fn limit_fetches(&mut self, limit: Arc<Semaphore>) {
+        self.fetch_limit = Some(limit);
+    }
+
+    /// Minimum poll interval the feed asked for through `<ttl>` or `Cache-Control: max-age`
+    pub fn advertised_ttl(&self) -> Option<Duration> {
+        self.advertised_ttl
+    }
+
+    /// URL the feed redirects to, if its last fetch ended up somewhere other than `source`
+    pub fn moved_to(&self) -> Option<&str> {
+        self.moved_to.as_deref()
+    }
+
+    /// Points the feed at a new URL, e.g. the target of a permanent redirect
+    pub fn relocate(&mut self, url: &str) {
+        self.source = canonical_url(url);
+        self.spec.url = self.source.clone();
+        self.moved_to = None;
+        self.last_build_date = None;
+    }
+
+    fn note_fetch(&mut self, loaded: &Loaded, channel: &Channel) {
+        // <ttl> is in minutes
+        let ttl = channel
+            .ttl()
+            .and_then(|t| t.trim().parse::<u64>().ok())
+            .map(|m| Duration::from_secs(m * 60));
+        self.advertised_ttl = ttl.max(loaded.max_age);
+        let title = channel.title().trim();
+        self.title = (!title.is_empty()).then(|| title.to_string());
+        self.note_final_url(&loaded.final_url);
+    }
+
+    /// Channel title of the last fetch, if it had one
+    pub fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+
+    fn note_final_url(&mut self, final_url: &str) {
+        let final_url = canonical_url(final_url);
+        if final_url == self.source {
+            self.moved_to = None;
+            return;
+        }
+        // Only logged when the target changes so a long lived redirect does not flood the log
+        if self.moved_to.as_deref() != Some(final_url.as_str()) {
+            warn!(
+                feed = redact_url(&self.source);
+                "Feed {} redirects to {}",
+                redact_url(&self.source),
+                redact_url(&final_url)
+            );
+        }
+        self.moved_to = Some(final_url);
     }
 
     pub fn source(&self) -> String {
         self.source.clone()
     }
 
-    pub fn items(&mut self) -> Vec<Item> {
-        std::mem::take(&mut self.items)
+    pub fn interval(&self) -> Option<Duration> {
+        self.spec.interval.map(Duration::from_secs)
+    }
+
+    /// Per-feed override of how old the newest item may get before the feed counts as dormant
+    pub fn dormant_after(&self) -> Option<Duration> {
+        self.spec
+            .dormant_after_days
+            .map(|days| Duration::from_secs(days * 24 * 60 * 60))
+    }
+
+    /// Shared flag that pauses notifications while the feed's refresh loop owns it
+    pub fn pause_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.paused)
+    }
+
+    fn should_notify(&self, item: &Item) -> bool {
+        if self.archive_only {
+            return false;
+        }
+
+        if self.paused.load(Ordering::Relaxed) {
+            debug!("Feed {} is paused", redact_url(&self.source));
+            return false;
+        }
+
+        if !passes_filters(&self.spec, item) {
+            debug!("Item filtered out for {}", redact_url(&self.source));
+            return false;
+        }
+
+        if self.is_too_old(item) {
+            debug!(
+                "Item older than max_age_days for {}",
+                redact_url(&self.source)
+            );
+            return false;
+        }
+
+        true
+    }
+
+    /// Claims the item's link so no other feed notifies the same article. Runs after the rate
+    /// limit so a suppressed item leaves the article to other feeds, the token taken for an
+    /// article another feed already delivered is handed back.
+    async fn claim_link(&mut self, item: &Item, store: &SeenStore, global_dedup: bool) -> bool {
+        if !global_dedup {
+            return true;
+        }
+        let Some(link) = item.link().and_then(normalize_link) else {
+            return true;
+        };
+        if store.claim_id(&format!("link:{}", link)).await {
+            return true;
+        }
+
+        debug!(
+            "Skipping duplicate of {} from {}",
+            link,
+            redact_url(&self.source)
+        );
+        if let Some(limit) = &mut self.notification_limit {
+            limit.refund();
+        }
+        false
+    }
+
+    /// Takes a notification from the rate limit, counting the item as suppressed when none is left
+    fn within_rate_limit(&mut self) -> bool {
+        let Some(limit) = &mut self.notification_limit else {
+            return true;
+        };
+        if limit.take() {
+            return true;
+        }
+        debug!("Rate limited notification for {}", redact_url(&self.source));
+        self.suppressed += 1;
+        false
     }
 
+    /// Items without a parseable date are never considered too old
+    fn is_too_old(&self, item: &Item) -> bool {
+        let Some(days) = self.spec.max_age_days else {
+            return false;
+        };
+        let cutoff = Utc::now() - TimeDelta::days(days as i64);
+        item.pub_date()
+            .and_then(parse_pub_date)
+            .is_some_and(|date| date < cutoff)
+    }
+
+    /// Marks `id` as the most recently seen, the least recently seen id is evicted once the cache
+    /// is full. A hit only bumps the id's generation instead of moving it in `seen_order`, which
+    /// keeps refreshes of large caches linear.
     fn remember(&mut self, id: String) {
-        if self.seen_items.contains(&id) {
+        self.generation += 1;
+        let generation = self.generation;
+        self.seen_order.push_back((generation, id.clone()));
+        if self.seen_items.insert(id, generation).is_some() {
+            // Drops the stale entries hits leave behind before they outnumber the live ones
+            if self.seen_order.len() > 2 * self.max_cache.max(1) {
+                let seen = &self.seen_items;
+                self.seen_order
+                    .retain(|(generation, id)| seen.get(id) == Some(generation));
+            }
             return;
         }
 
-        if self.seen_order.len() >= self.max_cache {
-            if let Some(old_id) = self.seen_order.pop_front() {
+        while self.seen_items.len() > self.max_cache {
+            let Some((generation, old_id)) = self.seen_order.pop_front() else {
+                break;
+            };
+            if self.seen_items.get(&old_id) == Some(&generation) {
                 self.seen_items.remove(&old_id);
             }
         }
+    }
+
+    async fn fetch_items(&mut self) -> Result<Vec<Item>, Box<dyn std::error::Error + Send + Sync>> {
+        if let Some(hosts) = &self.host_limit {
+            hosts.wait(&self.source).await;
+        }
+        let loaded = {
+            // Held for the download only, parsing and processing do not count against the limit
+            let _permit = match &self.fetch_limit {
+                Some(limit) => Some(limit.acquire().await?),
+                None => None,
+            };
+            self.fetcher.load(&self.spec, self.max_body).await?
+        };
+        let channel = Channel::read_from(&loaded.body[..])?;
+        self.note_fetch(&loaded, &channel);
 
-        self.seen_order.push_back(id.clone());
-        self.seen_items.insert(id);
+        // Cheap stand-in for a conditional GET: a channel that was not rebuilt since the last fetch
+        // has no new items, so hashing and looking up each of them can be skipped
+        let build_date = channel.last_build_date().map(|d| d.trim().to_string());
+        if build_date.is_some() && build_date == self.last_build_date {
+            trace!(
+                "Feed {} unchanged since {}, skipping items",
+                redact_url(&self.source),
+                build_date.unwrap_or_default()
+            );
+            return Ok(Vec::new());
+        }
+        self.last_build_date = build_date;
+        Ok(channel.into_items())
     }
 
-    pub async fn refresh(
+    /// Archives an item if it is new. Returns it with its id when it should be notified.
+    async fn process(
         &mut self,
+        mut item: Item,
         store: &SeenStore,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let content = reqwest::get(&self.source).await?.bytes().await?;
+        global_dedup: bool,
+    ) -> Option<(String, Item)> {
+        let id = item_hash(&item, self.spec.stable_hash, self.spec.hash_fallback);
 
-        let channel = Channel::read_from(&content[..])?;
-        for item in channel.into_items() {
-            let id = item_hash(&item);
+        // Resolved after hashing so ids of already seen items stay stable
+        if let Some(link) = item.link().and_then(|l| resolve_link(&self.source, l)) {
+            item.set_link(link);
+        }
 
-            // In memory route
-            if self.seen_items.contains(&id) {
-                continue;
+        // In memory route, still in the feed so it is kept over ids that dropped out of it
+        if self.seen_items.contains_key(&id) {
+            self.remember(id);
+            return None;
+        }
+
+        // Check backing Db
+        if store.is_seen(&id).await {
+            self.remember(id);
+            return None;
+        }
+
+        // Archived either way, only items that will be notified start out undelivered
+        let notify = self.should_notify(&item)
+            && self.within_rate_limit()
+            && self.claim_link(&item, store, global_dedup).await;
+        store.mark_seen(&item, &id, &self.source, notify).await;
+        self.remember(id.clone());
+
+        notify.then_some((id, item))
+    }
+
+    /// Refreshes the feed and sends each new item to `tx` as soon as it is archived instead of
+    /// collecting them first. Returns false if the event queue was closed.
+    pub async fn refresh_into(
+        &mut self,
+        store: &SeenStore,
+        global_dedup: bool,
+        tx: &Sender<FeedEvent>,
+        warn_after: Duration,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let Some(threshold) = self.spec.digest else {
+            for item in self.fetch_items().await? {
+                let Some((id, item)) = self.process(item, store, global_dedup).await else {
+                    continue;
+                };
+                if !send_event(tx, self.event(id, item, Vec::new()), warn_after).await {
+                    return Ok(false);
+                }
             }
+            return Ok(true);
+        };
 
-            // Check backing Db
-            if store.is_seen(&id).await {
-                self.remember(id);
-                continue;
+        // Digest feeds hold on to the refresh's items to decide whether to bundle them
+        let mut new = Vec::new();
+        for item in self.fetch_items().await? {
+            if let Some(processed) = self.process(item, store, global_dedup).await {
+                new.push(processed);
             }
+        }
+        if new.len() > 1 && new.len() >= threshold {
+            let rest = new.split_off(1);
+            let (id, item) = new.remove(0);
+            return Ok(send_event(tx, self.event(id, item, rest), warn_after).await);
+        }
+        for (id, item) in new {
+            if !send_event(tx, self.event(id, item, Vec::new()), warn_after).await {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
 
-            // Add to database
-            store.mark_seen(&item, &id, &self.source).await;
-            self.remember(id.clone());
-            self.items.push(item);
+    /// Refreshes the feed and collects the new items for `items()`, for callers that want a
+    /// refresh's items as a Vec. Goes through the same steps as `refresh_into` without sending.
+    #[allow(dead_code)]
+    pub async fn refresh(
+        &mut self,
+        store: &SeenStore,
+        global_dedup: bool,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        for item in self.fetch_items().await? {
+            if let Some(new) = self.process(item, store, global_dedup).await {
+                self.items.push(new);
+            }
         }
         Ok(())
     }
+
+    /// Drains the new items found by the last `refresh` as (id, item) pairs
+    #[allow(dead_code)]
+    pub fn items(&mut self) -> Vec<(String, Item)> {
+        std::mem::take(&mut self.items)
+    }
+
+    /// The items `items()` would return, without draining them
+    #[allow(dead_code)]
+    pub fn peek_items(&self) -> &[(String, Item)] {
+        &self.items
+    }
+
+    fn event(&self, id: String, item: Item, digest: Vec<(String, Item)>) -> FeedEvent {
+        FeedEvent {
+            id,
+            source: self.source(),
+            feed_title: self.title.clone(),
+            item,
+            webhook: self.spec.webhook.clone(),
+            color: self.spec.color,
+            digest,
+        }
+    }
+}
+
+/// A fetched feed body and what the response said about it
+pub struct Loaded {
+    /// UTF-8 feed document
+    pub body: Vec<u8>,
+    /// URL the body was finally served from after redirects
+    pub final_url: String,
+    /// `Cache-Control: max-age` of the response
+    pub max_age: Option<Duration>,
+}
+
+/// A feed answered with something other than 2xx after redirects were followed
+#[derive(Debug)]
+pub enum HttpStatusError {
+    /// 3xx left over when redirects are disabled or the limit was hit
+    Redirect(StatusCode),
+    /// 410, the feed was removed for good
+    Gone(StatusCode),
+    /// Any other 4xx, e.g. a 404 or expired credentials. Likely needs fixing on our side.
+    Client(StatusCode),
+    /// 5xx or 429, the server is struggling and a later retry may work
+    Server(StatusCode),
+}
+
+impl HttpStatusError {
+    fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::GONE => HttpStatusError::Gone(status),
+            StatusCode::TOO_MANY_REQUESTS => HttpStatusError::Server(status),
+            s if s.is_redirection() => HttpStatusError::Redirect(s),
+            s if s.is_client_error() => HttpStatusError::Client(s),
+            s => HttpStatusError::Server(s),
+        }
+    }
+
+    /// Whether retrying later is expected to work without anyone changing anything
+    pub fn is_transient(&self) -> bool {
+        matches!(self, HttpStatusError::Server(_))
+    }
 }
 
-fn item_hash(item: &Item) -> String {
+impl fmt::Display for HttpStatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpStatusError::Redirect(s) => write!(f, "feed answered {} without following it", s),
+            HttpStatusError::Gone(s) => write!(f, "feed is gone ({})", s),
+            HttpStatusError::Client(s) => write!(f, "feed rejected the request with {}", s),
+            HttpStatusError::Server(s) => write!(f, "feed server failed with {}", s),
+        }
+    }
+}
+
+impl std::error::Error for HttpStatusError {}
+
+/// Fetches the raw feed body. Only http(s) is accepted, test builds may also read file:// fixtures
+async fn load(
+    client: &Client,
+    spec: &FeedSpec,
+    max_body: usize,
+) -> Result<Loaded, Box<dyn std::error::Error + Send + Sync>> {
+    let url = Url::parse(&spec.url)?;
+    match url.scheme() {
+        "http" | "https" => {
+            let response = fetch(client, spec).await?;
+            // Error pages are often valid HTML that would only fail later as a parse error
+            if !response.status().is_success() {
+                return Err(HttpStatusError::from_status(response.status()).into());
+            }
+            let final_url = response.url().to_string();
+            let max_age = response
+                .headers()
+                .get(CACHE_CONTROL)
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_max_age);
+            Ok(Loaded {
+                body: to_utf8(read_body(response, max_body).await?),
+                final_url,
+                max_age,
+            })
+        }
+        #[cfg(test)]
+        "file" => {
+            let path = url.to_file_path().map_err(|_| "Invalid file URL")?;
+            let content = tokio::fs::read(path).await?;
+            check_body_size(content.len() as u64, max_body)?;
+            Ok(Loaded {
+                body: content,
+                final_url: spec.url.clone(),
+                max_age: None,
+            })
+        }
+        scheme => Err(format!(
+            "Unsupported feed URL scheme {}, only http and https are allowed",
+            scheme
+        )
+        .into()),
+    }
+}
+
+/// Reads `max-age` from a Cache-Control header, ignored when caching is ruled out
+fn parse_max_age(header: &str) -> Option<Duration> {
+    let directives: Vec<&str> = header.split(',').map(str::trim).collect();
+    if directives
+        .iter()
+        .any(|d| d.eq_ignore_ascii_case("no-cache") || d.eq_ignore_ascii_case("no-store"))
+    {
+        return None;
+    }
+    directives.iter().find_map(|d| {
+        let (key, value) = d.split_once('=')?;
+        if !key.trim().eq_ignore_ascii_case("max-age") {
+            return None;
+        }
+        value
+            .trim()
+            .trim_matches('"')
+            .parse()
+            .ok()
+            .map(Duration::from_secs)
+    })
+}
+
+/// Transcodes bodies whose XML prolog declares a non UTF-8 encoding, the parser only handles UTF-8
+fn to_utf8(content: Vec<u8>) -> Vec<u8> {
+    let Some(label) = declared_encoding(&content) else {
+        return content;
+    };
+    let Some(encoding) = Encoding::for_label(label.as_bytes()) else {
+        return content;
+    };
+    if encoding == UTF_8 {
+        return content;
+    }
+
+    debug!("Transcoding feed body from {}", encoding.name());
+    let (decoded, _, _) = encoding.decode(&content);
+    // The declaration has to follow the transcoding or the parser would reject the body
+    match decoded.find("?>") {
+        Some(end) => {
+            let (prolog, rest) = decoded.split_at(end);
+            format!("{}{}", prolog.replacen(&label, "UTF-8", 1), rest).into_bytes()
+        }
+        None => decoded.into_owned().into_bytes(),
+    }
+}
+
+/// Reads the `encoding` attribute of the XML declaration, if there is one
+fn declared_encoding(content: &[u8]) -> Option<String> {
+    let head = String::from_utf8_lossy(&content[..content.len().min(256)]);
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+    if !head.starts_with("<?xml") {
+        return None;
+    }
+
+    let prolog = &head[..head.find("?>")?];
+    let value = &prolog[prolog.find("encoding=")? + "encoding=".len()..];
+    let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let value = &value[1..];
+    Some(value[..value.find(quote)?].to_string())
+}
+
+/// Reads the response chunk by chunk so oversized bodies are dropped before they are fully downloaded
+async fn read_body(
+    mut response: Response,
+    max_body: usize,
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    if let Some(length) = response.content_length() {
+        check_body_size(length, max_body)?;
+    }
+
+    // Chunks are counted after decompression, so a small gzip bomb is caught as well
+    let mut content = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(reqwest::Error::without_url)?
+    {
+        // Checked before buffering so an oversized chunk is never kept
+        check_body_size((content.len() + chunk.len()) as u64, max_body)?;
+        content.extend_from_slice(&chunk);
+    }
+    Ok(content)
+}
+
+fn check_body_size(size: u64, max_body: usize) -> Result<(), String> {
+    if max_body > 0 && size > max_body as u64 {
+        return Err(format!(
+            "Feed body is larger than the {} byte limit (feeds.max_body_size)",
+            max_body
+        ));
+    }
+    Ok(())
+}
+
+async fn fetch(client: &Client, spec: &FeedSpec) -> Result<Response, reqwest::Error> {
+    let mut request = client.get(&spec.url);
+    if let Some(auth) = &spec.auth {
+        request = request.basic_auth(&auth.username, auth.password.as_ref());
+    }
+    for (name, value) in &spec.headers.0 {
+        request = request.header(name, value);
+    }
+    // reqwest errors embed the request URL, which may carry a token
+    request.send().await.map_err(reqwest::Error::without_url)
+}
+
+/// Checks the item's title and description against the feed's include/exclude keywords
+fn passes_filters(spec: &FeedSpec, item: &Item) -> bool {
+    if spec.include.is_empty() && spec.exclude.is_empty() {
+        return true;
+    }
+
+    let text = format!(
+        "{} {}",
+        item.title().unwrap_or_default(),
+        item.description().unwrap_or_default()
+    )
+    .to_lowercase();
+    let contains = |keyword: &String| text.contains(&keyword.to_lowercase());
+
+    if spec.exclude.iter().any(contains) {
+        return false;
+    }
+    spec.include.is_empty() || spec.include.iter().any(contains)
+}
+
+/// Identifies an item by its guid, or a hash of the `fallback` fields when it has none. Every field
+/// left out of the hash is an edit that no longer notifies again, and a way for two distinct items
+/// to end up with the same id. `stable` ignores the guid and hashes the normalized link and title,
+/// for feeds that regenerate guids on edit.
+fn item_hash(item: &Item, stable: bool, fallback: HashFallback) -> String {
+    if stable && let Some(id) = stable_hash(item) {
+        return id;
+    }
+
     if let Some(guid) = item.guid() {
         return guid.value().to_string();
     }
 
-    // fallback
+    // Title only hashing needs a title, anything else falls through to the full hash. Fields are
+    // fed in the same order as before so existing ids stay valid for the default.
+    let fallback = match (fallback, item.title()) {
+        (HashFallback::Title, None) => HashFallback::Full,
+        (fallback, _) => fallback,
+    };
     let mut hasher = Sha256::new();
-    if let Some(link) = item.link() {
+    if fallback != HashFallback::Title
+        && let Some(link) = item.link()
+    {
         hasher.update(link.as_bytes());
     }
     if let Some(title) = item.title() {
         hasher.update(title.as_bytes());
     }
-    if let Some(desc) = item.description() {
+    if fallback == HashFallback::Full
+        && let Some(desc) = item.description()
+    {
         hasher.update(desc.as_bytes());
     }
 
     hex::encode(hasher.finalize())
 }
+
+fn stable_hash(item: &Item) -> Option<String> {
+    let link = item
+        .link()
+        .map(|l| normalize_link(l).unwrap_or_else(|| l.trim().to_string()));
+    let title = item.title().map(str::trim);
+    if link.is_none() && title.is_none() {
+        return None;
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update(b"stable:");
+    hasher.update(link.unwrap_or_default().as_bytes());
+    // Separator so a link and title cannot run into each other
+    hasher.update([0u8]);
+    hasher.update(title.unwrap_or_default().as_bytes());
+    Some(hex::encode(hasher.finalize()))
+}
+
+/// Resolves a relative item link against the feed URL, None if it is already absolute
+fn resolve_link(base: &str, link: &str) -> Option<String> {
+    if Url::parse(link).is_ok() {
+        return None;
+    }
+    let joined = Url::parse(base).ok()?.join(link.trim()).ok()?;
+    Some(joined.to_string())
+}
+
+const TRACKING_PARAMS: &[&str] = &[
+    "fbclid", "gclid", "dclid", "msclkid", "mc_cid", "mc_eid", "igshid", "ref", "ref_src",
+];
+
+fn is_tracking_param(key: &str) -> bool {
+    key.starts_with("utm_") || TRACKING_PARAMS.contains(&key)
+}
+
+/// Normalizes an item link so the same article published through different feeds maps to one key.
+/// Lowercases the host, drops the fragment, tracking query parameters and any trailing slash.
+pub fn normalize_link(link: &str) -> Option<String> {
+    let mut url = Url::parse(link.trim()).ok()?;
+    url.set_fragment(None);
+
+    if let Some(host) = url.host_str() {
+        let host = host.to_lowercase();
+        url.set_host(Some(&host)).ok()?;
+    }
+
+    let query: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(k, _)| !is_tracking_param(k))
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+    if query.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(query.iter());
+    }
+
+    let path = url.path().trim_end_matches('/').to_string();
+    url.set_path(&path);
+
+    Some(url.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    const FEED_URL: &str = "https://example.com/feed.xml";
+
+    fn fixture(name: &str) -> Vec<u8> {
+        let path = format!("{}/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+        std::fs::read(&path).unwrap_or_else(|e| panic!("fixture {}: {}", path, e))
+    }
+
+    /// Serves a fixed body for every fetch, transcoded like `HttpFetcher` does
+    struct StubFetcher(Mutex<Vec<u8>>);
+
+    impl StubFetcher {
+        fn new(body: Vec<u8>) -> Arc<Self> {
+            Arc::new(Self(Mutex::new(body)))
+        }
+    }
+
+    impl FeedFetcher for StubFetcher {
+        fn load<'a>(
+            &'a self,
+            spec: &'a FeedSpec,
+            _max_body: usize,
+        ) -> BoxFuture<'a, Result<Loaded, Box<dyn std::error::Error + Send + Sync>>> {
+            let body = to_utf8(self.0.lock().unwrap().clone());
+            Box::pin(async move {
+                Ok(Loaded {
+                    body,
+                    final_url: spec.url.clone(),
+                    max_age: None,
+                })
+            })
+        }
+    }
+
+    /// Serves the basic fixture slowly and records the most loads it saw running at once
+    #[derive(Default)]
+    struct CountingFetcher {
+        in_flight: std::sync::atomic::AtomicUsize,
+        most: std::sync::atomic::AtomicUsize,
+    }
+
+    impl FeedFetcher for CountingFetcher {
+        fn load<'a>(
+            &'a self,
+            spec: &'a FeedSpec,
+            _max_body: usize,
+        ) -> BoxFuture<'a, Result<Loaded, Box<dyn std::error::Error + Send + Sync>>> {
+            Box::pin(async move {
+                let running = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                self.most.fetch_max(running, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                self.in_flight.fetch_sub(1, Ordering::SeqCst);
+                Ok(Loaded {
+                    body: fixture("basic.xml"),
+                    final_url: spec.url.clone(),
+                    max_age: None,
+                })
+            })
+        }
+    }
+
+    async fn stub_feed(spec: FeedSpec, body: Vec<u8>) -> RssFeed {
+        RssFeed::with_fetcher(spec, 300, 0, StubFetcher::new(body))
+            .await
+            .unwrap()
+    }
+
+    /// Canned HTTP response for a path of `mock_server`
+    struct Route {
+        path: &'static str,
+        head: String,
+        body: Vec<u8>,
+    }
+
+    impl Route {
+        fn new(path: &'static str, status: &str, headers: &str, body: Vec<u8>) -> Self {
+            let head = format!(
+                "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+                status,
+                headers,
+                body.len()
+            );
+            Route { path, head, body }
+        }
+    }
+
+    /// Answers requests on a local port with `routes` and returns its base URL, unknown paths get
+    /// a 404
+    async fn mock_server(routes: Vec<Route>) -> String {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let routes = Arc::new(routes);
+        tokio::spawn(async move {
+            while let Ok((conn, _)) = listener.accept().await {
+                let routes = routes.clone();
+                tokio::spawn(async move {
+                    let mut conn = BufReader::new(conn);
+                    let mut line = String::new();
+                    conn.read_line(&mut line).await.unwrap();
+                    let path = line
+                        .split_whitespace()
+                        .nth(1)
+                        .unwrap_or_default()
+                        .to_string();
+                    loop {
+                        let mut header = String::new();
+                        if conn.read_line(&mut header).await.unwrap() == 0 || header == "\r\n" {
+                            break;
+                        }
+                    }
+                    let conn = conn.get_mut();
+                    match routes.iter().find(|r| r.path == path) {
+                        Some(route) => {
+                            conn.write_all(route.head.as_bytes()).await.unwrap();
+                            conn.write_all(&route.body).await.unwrap();
+                        }
+                        None => {
+                            let not_found = "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+                            conn.write_all(not_found.as_bytes()).await.unwrap();
+                        }
+                    }
+                    let _ = conn.shutdown().await;
+                });
+            }
+        });
+        base
+    }
+
+    async fn http_feed(
+        url: String,
+        settings: &HttpSettings,
+        max_body: usize,
+    ) -> Result<RssFeed, Box<dyn std::error::Error + Send + Sync>> {
+        let fetcher = Arc::new(HttpFetcher(build_client(settings).unwrap()));
+        RssFeed::with_fetcher(FeedSpec::from(url), 300, max_body, fetcher).await
+    }
+
+    /// Refreshes `feed` once and returns the ids of the items it notified
+    async fn notified(feed: &mut RssFeed, store: &SeenStore, global_dedup: bool) -> Vec<String> {
+        let (tx, mut rx) = mpsc::channel(16);
+        assert!(
+            feed.refresh_into(store, global_dedup, &tx, Duration::from_secs(1))
+                .await
+                .unwrap()
+        );
+        drop(tx);
+        let mut ids = Vec::new();
+        while let Some(event) = rx.recv().await {
+            ids.push(event.item.guid().unwrap().value().to_string());
+        }
+        ids
+    }
+
+    #[tokio::test]
+    async fn notifies_new_items_once() {
+        let store = SeenStore::new(":memory:").await.unwrap();
+        let mut feed = stub_feed(FeedSpec::from(FEED_URL.to_string()), fixture("basic.xml")).await;
+        assert_eq!(feed.title(), Some("Example Feed"));
+
+        assert_eq!(
+            notified(&mut feed, &store, false).await,
+            ["post-1", "post-2", "post-3"]
+        );
+        assert!(notified(&mut feed, &store, false).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn seen_items_are_remembered_across_feed_instances() {
+        let store = SeenStore::new(":memory:").await.unwrap();
+        let spec = FeedSpec::from(FEED_URL.to_string());
+        let mut first = stub_feed(spec.clone(), fixture("basic.xml")).await;
+        assert_eq!(notified(&mut first, &store, false).await.len(), 3);
+
+        // A restarted feed starts with an empty in-memory cache and relies on the database
+        let mut restarted = stub_feed(spec, fixture("basic.xml")).await;
+        assert!(notified(&mut restarted, &store, false).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn paused_feed_archives_items_without_notifying() {
+        let store = SeenStore::new(":memory:").await.unwrap();
+        let fetcher = StubFetcher::new(fixture("basic.xml"));
+        let spec = FeedSpec::from(FEED_URL.to_string());
+        let mut feed = RssFeed::with_fetcher(spec, 300, 0, Arc::clone(&fetcher) as _)
+            .await
+            .unwrap();
+        let paused = feed.pause_handle();
+
+        paused.store(true, Ordering::Relaxed);
+        assert!(notified(&mut feed, &store, false).await.is_empty());
+        assert!(store.pending_items().await.is_empty());
+
+        // Items that came in while paused stay archived, only newer ones are notified
+        paused.store(false, Ordering::Relaxed);
+        *fetcher.0.lock().unwrap() = String::from_utf8(fixture("basic.xml"))
+            .unwrap()
+            .replace("<guid>post-", "<guid>later-")
+            .into_bytes();
+        assert_eq!(
+            notified(&mut feed, &store, false).await,
+            ["later-1", "later-2", "later-3"]
+        );
+    }
+
+    #[tokio::test]
+    async fn global_dedup_skips_links_delivered_by_another_feed() {
+        let store = SeenStore::new(":memory:").await.unwrap();
+        let mut original =
+            stub_feed(FeedSpec::from(FEED_URL.to_string()), fixture("basic.xml")).await;
+        assert_eq!(notified(&mut original, &store, true).await.len(), 3);
+
+        let mirrored = String::from_utf8(fixture("basic.xml"))
+            .unwrap()
+            .replace("<guid>post-", "<guid>mirror-");
+        let spec = FeedSpec::from("https://mirror.example.net/feed.xml".to_string());
+        let mut mirror = stub_feed(spec.clone(), mirrored.clone().into_bytes()).await;
+        // The relative link of post-3 resolves against the mirror host and is not a duplicate
+        assert_eq!(notified(&mut mirror, &store, true).await, ["mirror-3"]);
+
+        let spec = FeedSpec::from("https://example.com/other.xml".to_string());
+        let mut unrelated =
+            stub_feed(spec, mirrored.replace("mirror-", "other-").into_bytes()).await;
+        assert_eq!(notified(&mut unrelated, &store, false).await.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn include_and_exclude_filters() {
+        let store = SeenStore::new(":memory:").await.unwrap();
+        let spec = FeedSpec {
+            include: vec!["RUST".into()],
+            exclude: vec!["sponsored".into()],
+            ..FeedSpec::from(FEED_URL.to_string())
+        };
+        let mut feed = stub_feed(spec, fixture("basic.xml")).await;
+        assert_eq!(
+            notified(&mut feed, &store, false).await,
+            ["post-1", "post-3"]
+        );
+        // Filtered items are archived all the same and not offered again
+        assert!(store.is_seen("post-2").await);
+        assert!(notified(&mut feed, &store, false).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn latin1_feed_is_transcoded() {
+        let store = SeenStore::new(":memory:").await.unwrap();
+        let mut feed = stub_feed(FeedSpec::from(FEED_URL.to_string()), fixture("latin1.xml")).await;
+        assert_eq!(feed.title(), Some("Café Überblick"));
+
+        let (tx, mut rx) = mpsc::channel(4);
+        feed.refresh_into(&store, false, &tx, Duration::from_secs(1))
+            .await
+            .unwrap();
+        let event = rx.recv().await.unwrap();
+        assert_eq!(event.item.title(), Some("Crème brûlée für alle"));
+    }
+
+    #[test]
+    fn latin1_prolog_is_rewritten_to_utf8() {
+        let body = to_utf8(fixture("latin1.xml"));
+        let text = String::from_utf8(body).unwrap();
+        assert!(text.starts_with(r#"<?xml version="1.0" encoding="UTF-8"?>"#));
+        assert!(text.contains("Café Überblick"));
+
+        let channel = Channel::read_from(text.as_bytes()).unwrap();
+        assert_eq!(channel.items()[0].title(), Some("Crème brûlée für alle"));
+    }
+
+    #[test]
+    fn declared_encoding_reads_either_quote() {
+        assert_eq!(
+            declared_encoding(b"<?xml version='1.0' encoding='windows-1252'?><rss/>").as_deref(),
+            Some("windows-1252")
+        );
+        assert_eq!(declared_encoding(b"<?xml version=\"1.0\"?><rss/>"), None);
+        assert_eq!(declared_encoding(b"<rss/>"), None);
+    }
+
+    #[test]
+    fn utf8_bodies_are_left_alone() {
+        let body = fixture("basic.xml");
+        assert_eq!(to_utf8(body.clone()), body);
+    }
+
+    #[tokio::test]
+    async fn gzipped_responses_are_decoded() {
+        let base = mock_server(vec![Route::new(
+            "/feed.xml",
+            "200 OK",
+            "Content-Type: application/rss+xml\r\nContent-Encoding: gzip\r\n",
+            fixture("basic.xml.gz"),
+        )])
+        .await;
+        let store = SeenStore::new(":memory:").await.unwrap();
+
+        let mut feed = http_feed(format!("{}/feed.xml", base), &HttpSettings::default(), 0)
+            .await
+            .unwrap();
+        assert_eq!(feed.title(), Some("Example Feed"));
+        assert_eq!(notified(&mut feed, &store, false).await.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn oversized_bodies_are_rejected() {
+        let plain = fixture("basic.xml");
+        let gzipped = fixture("basic.xml.gz");
+        // Between the two sizes, so only the decoded gzip body is over the limit
+        let limit = gzipped.len() + 16;
+        assert!(limit < plain.len());
+        let base = mock_server(vec![
+            Route::new("/plain.xml", "200 OK", "", plain),
+            Route::new("/gzip.xml", "200 OK", "Content-Encoding: gzip\r\n", gzipped),
+        ])
+        .await;
+        let settings = HttpSettings::default();
+
+        for path in ["/plain.xml", "/gzip.xml"] {
+            let err = http_feed(format!("{}{}", base, path), &settings, limit)
+                .await
+                .err()
+                .unwrap_or_else(|| panic!("{} was accepted", path));
+            assert!(err.to_string().contains("byte limit"), "{}: {}", path, err);
+        }
+        assert!(
+            http_feed(format!("{}/plain.xml", base), &settings, 0)
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn permanent_redirect_is_noted_as_a_move() {
+        let base = mock_server(vec![
+            Route::new(
+                "/old.xml",
+                "301 Moved Permanently",
+                "Location: /new.xml\r\n",
+                Vec::new(),
+            ),
+            Route::new("/new.xml", "200 OK", "", fixture("basic.xml")),
+        ])
+        .await;
+        let old = format!("{}/old.xml", base);
+
+        let feed = http_feed(old.clone(), &HttpSettings::default(), 0)
+            .await
+            .unwrap();
+        assert_eq!(feed.moved_to(), Some(format!("{}/new.xml", base).as_str()));
+
+        let no_follow = HttpSettings {
+            max_redirects: 0,
+            ..HttpSettings::default()
+        };
+        let err = http_feed(old, &no_follow, 0).await.err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<HttpStatusError>(),
+            Some(HttpStatusError::Redirect(StatusCode::MOVED_PERMANENTLY))
+        ));
+    }
+
+    #[tokio::test]
+    async fn hash_fallback_decides_which_edits_notify_again() {
+        let without_guids = String::from_utf8(fixture("basic.xml"))
+            .unwrap()
+            .lines()
+            .filter(|line| !line.contains("<guid>"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let edited = without_guids.replace("A look at executors", "A look at executors, 812 views");
+        let moved = edited.replace("/posts/async", "/2025/06/async");
+
+        for (fallback, after_edit, after_move) in [
+            (HashFallback::Full, 1, 1),
+            (HashFallback::LinkTitle, 0, 1),
+            (HashFallback::Title, 0, 0),
+        ] {
+            let store = SeenStore::new(":memory:").await.unwrap();
+            let fetcher = StubFetcher::new(without_guids.clone().into_bytes());
+            let spec = FeedSpec {
+                hash_fallback: fallback,
+                ..FeedSpec::from(FEED_URL.to_string())
+            };
+            let mut feed = RssFeed::with_fetcher(spec, 300, 0, Arc::clone(&fetcher) as _)
+                .await
+                .unwrap();
+            feed.refresh(&store, false).await.unwrap();
+            assert_eq!(feed.items().len(), 3, "{:?}", fallback);
+
+            *fetcher.0.lock().unwrap() = edited.clone().into_bytes();
+            feed.refresh(&store, false).await.unwrap();
+            assert_eq!(feed.items().len(), after_edit, "{:?} edit", fallback);
+
+            *fetcher.0.lock().unwrap() = moved.clone().into_bytes();
+            feed.refresh(&store, false).await.unwrap();
+            assert_eq!(feed.items().len(), after_move, "{:?} move", fallback);
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_fetches_stay_within_the_shared_limit() {
+        let fetcher = Arc::new(CountingFetcher::default());
+        let limit = Arc::new(Semaphore::new(2));
+        let mut feeds = Vec::new();
+        for n in 0..6 {
+            let spec = FeedSpec::from(format!("https://example.com/{}.xml", n));
+            let mut feed = RssFeed::with_fetcher(spec, 300, 0, Arc::clone(&fetcher) as _)
+                .await
+                .unwrap();
+            feed.limit_fetches(Arc::clone(&limit));
+            feeds.push(feed);
+        }
+        fetcher.most.store(0, Ordering::SeqCst);
+
+        let store = SeenStore::new(":memory:").await.unwrap();
+        let refreshes = feeds.iter_mut().map(|feed| feed.refresh(&store, false));
+        for result in futures::future::join_all(refreshes).await {
+            result.unwrap();
+        }
+        assert_eq!(fetcher.most.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn buffered_refresh_collects_items_until_drained() {
+        let store = SeenStore::new(":memory:").await.unwrap();
+        let mut feed = stub_feed(FeedSpec::from(FEED_URL.to_string()), fixture("basic.xml")).await;
+
+        feed.refresh(&store, false).await.unwrap();
+        let ids: Vec<String> = feed
+            .items()
+            .into_iter()
+            .map(|(_, item)| item.guid().unwrap().value().to_string())
+            .collect();
+        assert_eq!(ids, ["post-1", "post-2", "post-3"]);
+        assert!(feed.items().is_empty());
+
+        feed.refresh(&store, false).await.unwrap();
+        assert!(feed.items().is_empty());
+    }
+
+    #[tokio::test]
+    async fn peek_items_reflects_a_refresh_without_draining() {
+        let store = SeenStore::new(":memory:").await.unwrap();
+        let mut feed = stub_feed(FeedSpec::from(FEED_URL.to_string()), fixture("basic.xml")).await;
+        assert!(feed.peek_items().is_empty());
+
+        feed.refresh(&store, false).await.unwrap();
+        assert_eq!(feed.peek_items().len(), 3);
+        assert_eq!(feed.peek_items().len(), 3);
+        let peeked: Vec<String> = feed.peek_items().iter().map(|(id, _)| id.clone()).collect();
+        let drained: Vec<String> = feed.items().into_iter().map(|(id, _)| id).collect();
+        assert_eq!(peeked, drained);
+        assert!(feed.peek_items().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rate_limited_items_leave_their_link_to_other_feeds() {
+        let store = SeenStore::new(":memory:").await.unwrap();
+        let limited = |url: &str| FeedSpec {
+            max_notifications_per_hour: Some(1),
+            ..FeedSpec::from(url.to_string())
+        };
+        let with_guids = |prefix: &str| {
+            String::from_utf8(fixture("basic.xml"))
+                .unwrap()
+                .replace("<guid>post-", &format!("<guid>{}-", prefix))
+                .into_bytes()
+        };
+
+        let mut original = stub_feed(limited(FEED_URL), fixture("basic.xml")).await;
+        original.limit_notifications(0);
+        assert_eq!(notified(&mut original, &store, true).await, ["post-1"]);
+        assert_eq!(original.take_suppressed(), 2);
+
+        // post-2 was suppressed above, so the mirror may still deliver it
+        let spec = FeedSpec::from("https://mirror.example.net/feed.xml".to_string());
+        let mut mirror = stub_feed(spec, with_guids("mirror")).await;
+        assert_eq!(
+            notified(&mut mirror, &store, true).await,
+            ["mirror-2", "mirror-3"]
+        );
+
+        // Duplicates hand their token back, which is then spent on the one new article
+        let mut copy = stub_feed(limited("https://example.com/copy.xml"), with_guids("copy")).await;
+        copy.limit_notifications(0);
+        assert_eq!(notified(&mut copy, &store, true).await, ["copy-3"]);
+        assert_eq!(copy.take_suppressed(), 0);
+    }
+
+    #[tokio::test]
+    async fn seen_ids_stay_cached_while_they_keep_showing_up() {
+        let spec = FeedSpec::from(FEED_URL.to_string());
+        let mut feed = RssFeed::with_fetcher(spec, 3, 0, StubFetcher::new(fixture("basic.xml")))
+            .await
+            .unwrap();
+        for id in ["a", "b", "c", "a", "d"] {
+            feed.remember(id.to_string());
+        }
+        // b was the least recently seen once a showed up again
+        let mut cached: Vec<&str> = feed.seen_items.keys().map(String::as_str).collect();
+        cached.sort();
+        assert_eq!(cached, ["a", "c", "d"]);
+
+        // Hits leave stale entries behind, they are dropped before piling up
+        for _ in 0..100 {
+            feed.remember("a".to_string());
+        }
+        assert!(feed.seen_order.len() <= 7, "{}", feed.seen_order.len());
+        feed.remember("e".to_string());
+        let mut cached: Vec<&str> = feed.seen_items.keys().map(String::as_str).collect();
+        cached.sort();
+        assert_eq!(cached, ["a", "d", "e"]);
+    }
+}
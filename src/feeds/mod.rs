@@ -1,2 +1,2 @@
-mod feed;
+pub mod feed;
 pub mod watcher;
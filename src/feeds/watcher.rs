@@ -1,213 +1,1405 @@
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
 
+use chrono::{DateTime, Utc};
 use futures::future::join_all;
-use log::{debug, error, trace};
+use log::{debug, error, info, trace, warn};
 use rss::Item;
+use sha2::{Digest, Sha256};
 use tokio::sync::{
+    Semaphore,
     mpsc::{self, Receiver, Sender},
     oneshot,
 };
 
-use crate::{db::SeenStore, feeds::feed::RssFeed};
+use crate::{
+    config::{EmbedColor, FeedSpec, QueuePolicy, canonical_url, redact_url},
+    db::{FeedRecord, SeenStore},
+    feeds::feed::{HostLimiter, HttpStatusError, RssFeed},
+};
 
 pub struct FeedEvent {
+    /// Archive id, used to confirm delivery
+    pub id: String,
     pub source: String,
+    /// Channel title of the feed, shown instead of `source` when known
+    pub feed_title: Option<String>,
     pub item: Item,
+    pub webhook: Option<String>,
+    /// Overrides the global embed color
+    pub color: Option<EmbedColor>,
+    /// Further (id, item) pairs sent together with `item` as one digest, empty for a single item
+    pub digest: Vec<(String, Item)>,
+}
+
+/// Emitted once when a feed enters the failing state and once when it recovers
+pub enum HealthEvent {
+    Failing {
+        source: String,
+        failures: u32,
+        error: String,
+    },
+    Recovered {
+        source: String,
+    },
+    /// The feed hit `max_failures` and its refresh loop has stopped
+    Disabled {
+        source: String,
+        failures: u32,
+    },
+    /// The feed redirected elsewhere and its refresh loop now follows the new URL
+    Moved {
+        from: String,
+        to: String,
+    },
+    /// The event queue was closed under a refresh loop, nothing can be delivered anymore
+    QueueClosed {
+        source: String,
+    },
+    /// The feed still refreshes but has not published anything since `newest`
+    Dormant {
+        source: String,
+        newest: DateTime<Utc>,
+    },
+    /// A refresh archived `suppressed` items without notifying them, the feed hit its hourly limit
+    RateLimited {
+        source: String,
+        suppressed: usize,
+    },
+}
+
+pub enum RemoveOutcome {
+    /// The feed's refresh loop was running and has been stopped
+    Stopped,
+    /// The feed was stored but had no running loop, e.g. it failed to start or was disabled
+    DatabaseOnly,
+    NotFound,
+}
+
+/// Tunables shared by every feed refresh loop
+#[derive(Clone, Copy)]
+pub struct RefreshSettings {
+    pub normal_sleep: Duration,
+    pub fail_sleep: Duration,
+    pub cache_size: usize,
+    /// Largest accepted feed body in bytes, 0 means unlimited
+    pub max_body_size: usize,
+    pub global_dedup: bool,
+    /// Consecutive failures before a feed is reported as failing, 0 disables reporting
+    pub failure_threshold: u32,
+    /// Consecutive failures before a feed is disabled, 0 keeps retrying forever
+    pub max_failures: u32,
+    /// How long a send may block on a full event queue before it is logged, zero disables it
+    pub backpressure_warn: Duration,
+    /// Re-key feeds that redirect elsewhere to their final URL
+    pub follow_moved: bool,
+    /// Never poll a feed more often than its `<ttl>` or `Cache-Control: max-age` allows
+    pub honor_ttl: bool,
+    /// Feeds downloading at once across all refresh loops, 0 means unlimited
+    pub max_concurrent_fetches: usize,
+    /// Percentage of the interval a loop may wait before its first refresh
+    pub startup_jitter: u8,
+    /// Age of the newest item at which a feed counts as dormant, zero disables it
+    pub dormant_after: Duration,
+    /// Archive new items as delivered without emitting any events
+    pub archive_only: bool,
+    /// Notifications a feed may send per hour, 0 means unlimited
+    pub max_notifications_per_hour: u32,
+    /// Least time between two requests to the same host, zero disables the spacing
+    pub min_host_interval: Duration,
+}
+
+/// Size and overflow handling of the event queue between the refresh loops and the server
+pub struct QueueSettings {
+    pub size: usize,
+    /// Percentage of `size` at which a falling behind warning is logged, 0 disables it
+    pub high_water: u8,
+    pub policy: QueuePolicy,
+}
+
+/// Controls for a running refresh loop
+struct FeedHandle {
+    quit: oneshot::Sender<()>,
+    paused: Arc<AtomicBool>,
+    stats: Arc<FeedStats>,
+    started: DateTime<Utc>,
+    /// Age of the newest item at which the feed counts as dormant, zero never does
+    dormant_after: Duration,
+}
+
+/// Refresh intervals without a successful refresh before a feed counts as stale
+const STALE_INTERVALS: u32 = 3;
+
+/// Refresh results published by a loop for the command handlers to read
+#[derive(Default)]
+struct FeedStats {
+    failures: AtomicU32,
+    /// Unix timestamp of the last successful refresh, 0 if there was none yet
+    last_success: AtomicI64,
+    /// Seconds the loop currently waits between refreshes, including a longer `<ttl>`
+    interval: AtomicU64,
+    title: Mutex<Option<String>>,
+}
+
+impl FeedStats {
+    fn record_failure(&self, failures: u32) {
+        self.failures.store(failures, Ordering::Relaxed);
+    }
+
+    fn record_success(&self, title: Option<&str>) {
+        self.failures.store(0, Ordering::Relaxed);
+        self.last_success
+            .store(Utc::now().timestamp(), Ordering::Relaxed);
+        if let Some(title) = title {
+            *self.title.lock().unwrap() = Some(title.to_string());
+        }
+    }
+
+    fn record_interval(&self, interval: Duration) {
+        self.interval.store(interval.as_secs(), Ordering::Relaxed);
+    }
+
+    /// Carries over what the previous run knew until the loop refreshes for the first time
+    fn restore(&self, record: FeedRecord) {
+        if let Some(last_success) = record.last_success {
+            self.last_success
+                .store(last_success.timestamp(), Ordering::Relaxed);
+        }
+        *self.title.lock().unwrap() = record.title;
+    }
+}
+
+/// Point in time view of a followed feed
+pub struct FeedSnapshot {
+    pub url: String,
+    pub title: Option<String>,
+    pub paused: bool,
+    pub last_success: Option<DateTime<Utc>>,
+    /// Consecutive failed refreshes
+    pub failures: u32,
+    /// No successful refresh for `STALE_INTERVALS` intervals, counted from the loop start if it
+    /// never succeeded
+    pub stale: bool,
 }
 
 pub struct RssManager {
     event_sender: Sender<FeedEvent>,
     events: Receiver<FeedEvent>,
-    feed_list: HashMap<String, oneshot::Sender<()>>,
-    normal_sleep: Duration,
-    fail_sleep: Duration,
+    health_sender: Sender<HealthEvent>,
+    feed_list: HashMap<String, FeedHandle>,
+    /// Feeds that failed to load at startup, keyed to the quit signal of their retry task
+    starting: HashMap<String, oneshot::Sender<()>>,
+    started: Receiver<RssFeed>,
+    disabled: HashSet<String>,
+    settings: RefreshSettings,
     seen_store: Arc<SeenStore>,
+    fetch_limit: Arc<Semaphore>,
+    host_limit: Arc<HostLimiter>,
+    queue_policy: QueuePolicy,
+    /// Queued events at which the queue counts as backed up, 0 disables the warning
+    high_water: usize,
+    above_high_water: bool,
 }
 
 impl RssManager {
     pub async fn new(
         database_path: &str,
-        rss_feeds: &[String],
-        queue_size: usize,
-        sleep_interval: Duration,
+        rss_feeds: &[FeedSpec],
+        queue: QueueSettings,
+        settings: RefreshSettings,
+        health_sender: Sender<HealthEvent>,
     ) -> Result<(Self, Vec<String>), sqlx::Error> {
-        let fail_sleep = std::time::Duration::from_secs(60 * 60);
-        let (send, recv) = mpsc::channel(queue_size);
+        let (send, recv) = mpsc::channel(queue.size);
+        let high_water = match queue.high_water {
+            0 => 0,
+            percent => (queue.size * percent as usize / 100).max(1),
+        };
         let db = SeenStore::new(database_path).await?;
 
         // --------- FEED SETUP ---------
-        // Fetch feeds from database so that we can push new feeds as we want
-        let mut feed_list = db.get_feeds().await;
-        feed_list.extend_from_slice(rss_feeds);
+        // Rewrite feeds stored before URLs were canonicalized so add/remove keys line up. Renaming
+        // keeps the paused, disabled and options columns with the feed.
+        let stored = db
+            .get_feeds()
+            .await
+            .into_iter()
+            .chain(db.get_disabled_feeds().await);
+        for feed in stored.filter(|f| canonical_url(f) != *f) {
+            db.rename_feed(&feed, &canonical_url(&feed)).await;
+        }
 
-        let (feeds, failed_urls) = resolve_feeds(feed_list).await;
+        // Config feeds come first so their options win over the bare URLs stored in the database
+        let disabled: HashSet<String> = db
+            .get_disabled_feeds()
+            .await
+            .iter()
+            .map(|f| canonical_url(f))
+            .collect();
+        let mut known = HashSet::new();
+        let mut options = db.feed_options().await;
+        let feed_list: Vec<FeedSpec> = rss_feeds
+            .iter()
+            .filter(|&spec| !disabled.contains(&spec.url))
+            .cloned()
+            // Fetch feeds from database so that we can push new feeds as we want
+            .chain(db.get_feeds().await.into_iter().map(|url| {
+                let stored = options.remove(&url).unwrap_or_default();
+                stored_spec(url, &stored)
+            }))
+            .filter(|spec| known.insert(spec.url.clone()))
+            .collect();
+        // Where undelivered items are re-emitted to, with the same options their feed runs with.
+        // Disabled config feeds are not in the list but may still have items pending.
+        let destinations: HashMap<String, (Option<String>, Option<EmbedColor>)> = rss_feeds
+            .iter()
+            .chain(feed_list.iter())
+            .map(|spec| (spec.url.clone(), (spec.webhook.clone(), spec.color)))
+            .collect();
+
+        let host_limit = Arc::new(HostLimiter::new(settings.min_host_interval));
+        let (feeds, failed_specs) = resolve_feeds(
+            feed_list,
+            settings.cache_size,
+            settings.max_body_size,
+            settings.max_concurrent_fetches,
+            &host_limit,
+        )
+        .await;
 
         // Sync database with feeds
         db.push_feeds(feeds.iter().map(|f| f.source()).collect())
             .await;
 
+        // Collected before any refresh loop runs so freshly archived items are not emitted twice
+        let pending = db.pending_items().await;
+        let paused: HashSet<String> = db.get_paused_feeds().await.into_iter().collect();
+        let mut records = db.feed_records().await;
+        let titles: HashMap<String, String> = records
+            .iter()
+            .filter_map(|(feed, record)| Some((feed.clone(), record.title.clone()?)))
+            .collect();
+
         // --------- READING SETUP ---------
         let seen_mutex = Arc::new(db);
+        let fetch_limit = Arc::new(fetch_semaphore(settings.max_concurrent_fetches));
         let mut feed_list = HashMap::new();
         // Clone every single feed and run their synching in tasks to get rid of as much blocking as possible
         // Blocking will still occur when they use the SeenStore
         for feed in feeds {
-            // Creates a URL => oneshot sender entry in the feed_list
-            feed_list.insert(
-                feed.source().to_string(),
-                feed_refresh_loop(
-                    send.clone(),
-                    Arc::clone(&seen_mutex),
-                    feed,
-                    sleep_interval,
-                    fail_sleep,
-                ),
+            // Creates a URL => handle entry in the feed_list
+            let source = feed.source();
+            let handle = feed_refresh_loop(
+                send.clone(),
+                health_sender.clone(),
+                Arc::clone(&seen_mutex),
+                Arc::clone(&fetch_limit),
+                Arc::clone(&host_limit),
+                feed,
+                settings,
             );
+            handle
+                .paused
+                .store(paused.contains(&source), Ordering::Relaxed);
+            if let Some(record) = records.remove(&source) {
+                handle.stats.restore(record);
+            }
+            if let Some(old) = feed_list.insert(source.clone(), handle) {
+                stop_refresh_loop(&source, old.quit);
+            }
+        }
+
+        // Feeds whose host was briefly down at boot keep being retried instead of being dropped
+        let (started_sender, started) = mpsc::channel(16);
+        let mut failed_urls = Vec::with_capacity(failed_specs.len());
+        let mut starting = HashMap::new();
+        for spec in failed_specs {
+            failed_urls.push(spec.url.clone());
+            let quit = feed_start_retry(spec.clone(), started_sender.clone(), settings);
+            starting.insert(spec.url, quit);
+        }
+
+        // Re-emit items that were archived but never confirmed delivered, e.g. after a crash
+        if !pending.is_empty() && settings.archive_only {
+            info!(
+                "Archive only, not re-emitting {} undelivered items",
+                pending.len()
+            );
+        } else if !pending.is_empty() {
+            info!("Re-emitting {} undelivered items", pending.len());
+            let tx = send.clone();
+            tokio::spawn(async move {
+                for p in pending {
+                    let (webhook, color) = destinations
+                        .get(&p.feed_source)
+                        .cloned()
+                        .unwrap_or_default();
+                    let event = FeedEvent {
+                        item: p.to_item(),
+                        webhook,
+                        color,
+                        id: p.id,
+                        feed_title: titles.get(&p.feed_source).cloned(),
+                        source: p.feed_source,
+                        digest: Vec::new(),
+                    };
+                    if tx.send(event).await.is_err() {
+                        error!("Event queue closed while re-emitting undelivered items");
+                        break;
+                    }
+                }
+            });
         }
 
         Ok((
             Self {
                 event_sender: send,
                 events: recv,
-                normal_sleep: sleep_interval,
-                fail_sleep: fail_sleep,
+                health_sender,
+                settings,
                 seen_store: seen_mutex,
-                feed_list: feed_list,
+                fetch_limit,
+                host_limit,
+                queue_policy: queue.policy,
+                high_water,
+                above_high_water: false,
+                feed_list,
+                starting,
+                started,
+                disabled,
             },
             failed_urls,
         ))
     }
 
+    /// Starts following a feed. Returns false without fetching anything if it is already followed
+    /// or disabled, disabled feeds only come back through `resume_feed` which clears the flag.
     pub async fn add_feed(
         &mut self,
-        url: &str,
+        spec: FeedSpec,
     ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
-        if self.feed_list.contains_key(url) {
+        if self.feed_list.contains_key(&spec.url) || self.disabled.contains(&spec.url) {
             return Ok(false);
         }
 
-        let feed = RssFeed::new(url.to_string(), 300).await?;
-        let changed = self.seen_store.push_feeds(vec![feed.source()]).await;
+        self.load_feed(spec).await?;
+        Ok(true)
+    }
 
-        let old = self.feed_list.insert(
-            feed.source(),
-            feed_refresh_loop(
-                self.event_sender.clone(),
-                Arc::clone(&self.seen_store),
-                feed,
-                self.normal_sleep,
-                self.fail_sleep,
-            ),
+    /// Adds a feed given at runtime and stores the options it was added with, so a restart runs
+    /// it the same way. Returns false like `add_feed` if it was not started.
+    pub async fn follow_feed(
+        &mut self,
+        spec: FeedSpec,
+        options: &[(String, String)],
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let url = spec.url.clone();
+        let added = self.add_feed(spec).await?;
+        if added && !options.is_empty() {
+            self.seen_store.set_feed_options(&url, options).await;
+        }
+        Ok(added)
+    }
+
+    async fn load_feed(
+        &mut self,
+        spec: FeedSpec,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let feed =
+            RssFeed::new(spec, self.settings.cache_size, self.settings.max_body_size).await?;
+        self.start_feed(feed).await;
+        Ok(())
+    }
+
+    /// Runs a loaded feed, replacing any loop or startup retry it already had
+    async fn start_feed(&mut self, feed: RssFeed) {
+        let source = feed.source();
+        // The retry task may already be gone after handing over this feed
+        if let Some(quit) = self.starting.remove(&source) {
+            let _ = quit.send(());
+        }
+        self.seen_store.push_feeds(vec![source.clone()]).await;
+        let paused = self.seen_store.get_paused_feeds().await.contains(&source);
+
+        let handle = feed_refresh_loop(
+            self.event_sender.clone(),
+            self.health_sender.clone(),
+            Arc::clone(&self.seen_store),
+            Arc::clone(&self.fetch_limit),
+            Arc::clone(&self.host_limit),
+            feed,
+            self.settings,
         );
+        handle.paused.store(paused, Ordering::Relaxed);
+        if let Some(record) = self.seen_store.feed_records().await.remove(&source) {
+            handle.stats.restore(record);
+        }
+        if let Some(old) = self.feed_list.insert(source.clone(), handle) {
+            stop_refresh_loop(&source, old.quit);
+        }
+    }
+
+    /// Re-enables a feed that was disabled after too many failures
+    pub async fn resume_feed(
+        &mut self,
+        spec: FeedSpec,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        if !self.disabled.contains(&spec.url) {
+            return Ok(false);
+        }
 
-        Ok(changed > 0 && old.is_none())
+        let url = spec.url.clone();
+        self.load_feed(spec).await?;
+        self.disabled.remove(&url);
+        self.seen_store.set_feed_disabled(&url, false).await;
+        Ok(true)
     }
 
-    // Maybe remove it from the feeds thing too? idk
-    pub async fn remove_feed(&mut self, url: &str) -> bool {
-        self.seen_store.remove_feeds(vec![url.to_string()]).await;
+    /// Pauses or unpauses notifications for a followed feed. Returns false if it is not followed.
+    pub async fn set_paused(&self, url: &str, paused: bool) -> bool {
+        let url = canonical_url(url);
+        let Some(handle) = self.feed_list.get(&url) else {
+            return false;
+        };
+        handle.paused.store(paused, Ordering::Relaxed);
+        self.seen_store.set_feed_paused(&url, paused).await;
+        true
+    }
 
-        if let Some(oneshot) = self.feed_list.remove(url) {
-            debug!("Found feed {}", url);
-            if let Err(e) = oneshot.send(()) {
-                error!("Error sending oneshot to quit: {:?}", e);
-            }
-            return true;
+    pub fn is_paused(&self, url: &str) -> bool {
+        self.feed_list
+            .get(&canonical_url(url))
+            .is_some_and(|h| h.paused.load(Ordering::Relaxed))
+    }
+
+    /// Re-keys a feed whose refresh loop moved to a new URL. If the new URL is already followed
+    /// the moved loop is stopped instead so the feed is not polled twice.
+    pub fn rename_feed(&mut self, from: &str, to: &str) {
+        let Some(handle) = self.feed_list.remove(from) else {
+            return;
+        };
+        if self.feed_list.contains_key(to) {
+            info!(
+                feed = redact_url(from);
+                "Feed {} moved to already followed {}, stopping it",
+                redact_url(from),
+                redact_url(to)
+            );
+            stop_refresh_loop(from, handle.quit);
+            return;
         }
-        debug!("Did not find feed {}", url);
-        return false;
+        self.feed_list.insert(to.to_string(), handle);
     }
 
+    /// Forgets the refresh loop of a feed that disabled itself
+    pub fn mark_disabled(&mut self, url: &str) {
+        self.feed_list.remove(url);
+        self.disabled.insert(url.to_string());
+    }
+
+    pub async fn remove_feed(&mut self, url: &str) -> RemoveOutcome {
+        let url = &canonical_url(url);
+        let removed_rows = self.seen_store.remove_feeds(vec![url.to_string()]).await;
+        self.disabled.remove(url);
+
+        if let Some(handle) = self.feed_list.remove(url) {
+            debug!("Found feed {}", redact_url(url));
+            stop_refresh_loop(url, handle.quit);
+            return RemoveOutcome::Stopped;
+        }
+        if let Some(quit) = self.starting.remove(url) {
+            debug!("Stopped retrying feed {}", redact_url(url));
+            stop_refresh_loop(url, quit);
+            return RemoveOutcome::Stopped;
+        }
+        if removed_rows > 0 {
+            debug!("Feed {} was only stored in the database", redact_url(url));
+            return RemoveOutcome::DatabaseOnly;
+        }
+        debug!("Did not find feed {}", redact_url(url));
+        RemoveOutcome::NotFound
+    }
+
+    /// Next feed event. Feeds whose startup retry succeeded are started while waiting for it.
     pub async fn next(&mut self) -> Option<FeedEvent> {
-        self.events.recv().await
+        loop {
+            tokio::select! {
+                event = self.events.recv() => return event.map(|event| self.take_event(event)),
+                Some(feed) = self.started.recv() => {
+                    // A retry that finished after its feed was removed is dropped
+                    if self.starting.contains_key(&feed.source()) {
+                        info!("Feed {} loaded after failing at startup", redact_url(&feed.source()));
+                        self.start_feed(feed).await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Applies the queue policy to a received event and logs when the queue crosses its high-water mark
+    fn take_event(&mut self, mut event: FeedEvent) -> FeedEvent {
+        let capacity = self.events.max_capacity();
+        // The received event freed the only slot, so feeds were blocked on a full queue
+        if self.queue_policy == QueuePolicy::DropOldest && self.events.len() + 1 >= capacity {
+            let keep = match self.high_water {
+                0 => capacity / 2,
+                n => n.min(capacity - 1),
+            };
+            let mut dropped = 0;
+            while self.events.len() >= keep {
+                let Ok(newer) = self.events.try_recv() else {
+                    break;
+                };
+                debug!("Dropped {} from {}", event.id, redact_url(&event.source));
+                event = newer;
+                dropped += 1;
+            }
+            if dropped > 0 {
+                warn!(
+                    "Event queue full, dropped the {} oldest events, they are re-emitted on the next start",
+                    dropped
+                );
+            }
+        }
+
+        if self.high_water > 0 {
+            let depth = self.events.len() + 1;
+            if !self.above_high_water && depth >= self.high_water {
+                self.above_high_water = true;
+                warn!(
+                    "Event queue at {}/{} events, delivery is falling behind",
+                    depth, capacity
+                );
+            } else if self.above_high_water && depth <= self.high_water / 2 {
+                self.above_high_water = false;
+                info!("Event queue drained to {}/{} events", depth, capacity);
+            }
+        }
+        event
     }
 
     pub fn len(&self) -> usize {
         self.feed_list.len()
     }
 
+    /// Events currently waiting in the queue and the queue's capacity
+    pub fn queue_depth(&self) -> (usize, usize) {
+        let max = self.event_sender.max_capacity();
+        (max - self.event_sender.capacity(), max)
+    }
+
+    /// Followed feeds, including ones still retrying after failing at startup
     pub fn feeds(&self) -> Vec<String> {
-        self.feed_list.iter().map(|(f, _)| f.to_string()).collect()
+        self.feed_list
+            .keys()
+            .chain(self.starting.keys())
+            .cloned()
+            .collect()
+    }
+
+    /// Every stored feed, including disabled ones that have no refresh loop
+    pub fn known_feeds(&self) -> Vec<String> {
+        let mut feeds = self.feeds();
+        feeds.extend(self.disabled.iter().cloned());
+        feeds
+    }
+
+    /// Feeds that failed to load at startup and are still being retried
+    pub fn starting_feeds(&self) -> Vec<String> {
+        self.starting.keys().cloned().collect()
+    }
+
+    pub fn store(&self) -> &SeenStore {
+        &self.seen_store
+    }
+
+    pub fn shared_store(&self) -> Arc<SeenStore> {
+        Arc::clone(&self.seen_store)
+    }
+
+    /// Consistent view of every followed feed, sorted by URL
+    pub fn snapshot(&self) -> Vec<FeedSnapshot> {
+        let now = Utc::now();
+        let mut feeds: Vec<FeedSnapshot> = self
+            .feed_list
+            .iter()
+            .map(|(url, handle)| {
+                let last_success = match handle.stats.last_success.load(Ordering::Relaxed) {
+                    0 => None,
+                    ts => DateTime::from_timestamp(ts, 0),
+                };
+                let since = (now - last_success.unwrap_or(handle.started))
+                    .to_std()
+                    .unwrap_or_default();
+                let interval = handle.stats.interval.load(Ordering::Relaxed);
+                let stale_after = Duration::from_secs(interval) * STALE_INTERVALS;
+                FeedSnapshot {
+                    url: url.clone(),
+                    title: handle.stats.title.lock().unwrap().clone(),
+                    paused: handle.paused.load(Ordering::Relaxed),
+                    last_success,
+                    failures: handle.stats.failures.load(Ordering::Relaxed),
+                    stale: since > stale_after,
+                }
+            })
+            .collect();
+        feeds.sort_by(|a, b| a.url.cmp(&b.url));
+        feeds
+    }
+
+    /// Followed feeds whose newest item is older than their dormant threshold, with that item's
+    /// date. Feeds that never had an item are left out.
+    pub async fn dormant_feeds(&self) -> Vec<(String, DateTime<Utc>)> {
+        let newest = self.seen_store.newest_items().await;
+        let now = Utc::now();
+        let mut dormant: Vec<(String, DateTime<Utc>)> = self
+            .feed_list
+            .iter()
+            .filter(|(_, handle)| !handle.dormant_after.is_zero())
+            .filter_map(|(url, handle)| {
+                let newest = *newest.get(url)?;
+                let age = (now - newest).to_std().unwrap_or_default();
+                (age > handle.dormant_after).then(|| (url.clone(), newest))
+            })
+            .collect();
+        dormant.sort();
+        dormant
+    }
+
+    pub fn is_disabled(&self, url: &str) -> bool {
+        self.disabled.contains(&canonical_url(url))
+    }
+
+    pub fn disabled_feeds(&self) -> Vec<String> {
+        self.disabled.iter().cloned().collect()
     }
 }
 
-pub async fn resolve_feeds(feeds: Vec<String>) -> (Vec<RssFeed>, Vec<String>) {
-    let feed_futs = feeds.iter().map(|url| {
-        let url = (*url).to_string();
-        async move {
-            let result = RssFeed::new(url.clone(), 300).await;
-            (url, result)
-        }
-    });
-    let results: Vec<(String, Result<RssFeed, _>)> = join_all(feed_futs).await;
-    let feeds: Vec<RssFeed> = results
+/// Splits `desired` against `current` into feeds to start and feeds to stop
+pub fn diff_feeds(current: &[String], desired: &[FeedSpec]) -> (Vec<FeedSpec>, Vec<String>) {
+    let added = desired
         .iter()
-        .filter_map(|(_, res)| res.as_ref().ok().cloned())
+        .filter(|spec| !current.contains(&spec.url))
+        .cloned()
         .collect();
-
-    let failed_urls: Vec<String> = results
-        .into_iter()
-        .filter_map(|(url, res)| if res.is_err() { Some(url) } else { None })
+    let removed = current
+        .iter()
+        .filter(|url| !desired.iter().any(|spec| &spec.url == *url))
+        .cloned()
         .collect();
-    return (feeds, failed_urls);
+    (added, removed)
+}
+
+/// Spec of a feed stored in the database with the options it was added with. Options that no
+/// longer parse are dropped so the feed still starts.
+pub fn stored_spec(url: String, options: &[(String, String)]) -> FeedSpec {
+    FeedSpec::with_options(url.clone(), options).unwrap_or_else(|e| {
+        warn!(
+            "Ignoring stored options of feed {}: {}",
+            redact_url(&url),
+            e
+        );
+        FeedSpec::from(url)
+    })
+}
+
+/// Semaphore for `max_concurrent_fetches`, where 0 means unlimited
+fn fetch_semaphore(max_concurrent: usize) -> Semaphore {
+    match max_concurrent {
+        0 => Semaphore::new(Semaphore::MAX_PERMITS),
+        n => Semaphore::new(n),
+    }
+}
+
+/// Loads every feed with at most `max_concurrent` downloads at once and requests to one host
+/// spaced out by `hosts`, returning the loaded feeds and the specs of those that failed
+pub async fn resolve_feeds(
+    feeds: Vec<FeedSpec>,
+    cache_size: usize,
+    max_body: usize,
+    max_concurrent: usize,
+    hosts: &HostLimiter,
+) -> (Vec<RssFeed>, Vec<FeedSpec>) {
+    let limit = fetch_semaphore(max_concurrent);
+    let limit = &limit;
+    let feed_futs = feeds.into_iter().map(|spec| async move {
+        hosts.wait(&spec.url).await;
+        let _permit = limit.acquire().await;
+        let result = RssFeed::new(spec.clone(), cache_size, max_body).await;
+        (spec, result)
+    });
+    let results: Vec<(FeedSpec, Result<RssFeed, _>)> = join_all(feed_futs).await;
+
+    // Consume the results so constructed feeds are moved out instead of cloned
+    let mut feeds = Vec::with_capacity(results.len());
+    let mut failed = Vec::new();
+    for (spec, res) in results {
+        match res {
+            Ok(feed) => feeds.push(feed),
+            Err(_) => failed.push(spec),
+        }
+    }
+    (feeds, failed)
+}
+
+/// Retries loading a feed that failed at startup every `fail_sleep` and hands it to the manager
+/// once it loads. Stopped through the returned sender like a refresh loop.
+fn feed_start_retry(
+    spec: FeedSpec,
+    started: Sender<RssFeed>,
+    settings: RefreshSettings,
+) -> oneshot::Sender<()> {
+    let (sender, mut quit_recv) = oneshot::channel();
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = &mut quit_recv => break,
+                _ = tokio::time::sleep(settings.fail_sleep) => {}
+            }
+            match RssFeed::new(spec.clone(), settings.cache_size, settings.max_body_size).await {
+                Ok(feed) => {
+                    let _ = started.send(feed).await;
+                    break;
+                }
+                Err(e) => debug!("Feed {} still fails to load: {}", redact_url(&spec.url), e),
+            }
+        }
+    });
+    sender
+}
+
+/// Signals a refresh loop to quit. Replaced or removed senders should always go through here.
+fn stop_refresh_loop(url: &str, quit: oneshot::Sender<()>) {
+    if let Err(e) = quit.send(()) {
+        error!("Error sending oneshot to quit {}: {:?}", redact_url(url), e);
+    }
 }
 
 fn feed_refresh_loop(
     tx: Sender<FeedEvent>,
+    health: Sender<HealthEvent>,
     store: Arc<SeenStore>,
+    fetch_limit: Arc<Semaphore>,
+    host_limit: Arc<HostLimiter>,
     mut feed: RssFeed,
-    normal_sleep: Duration,
-    fail_sleep: Duration,
-) -> oneshot::Sender<()> {
+    mut settings: RefreshSettings,
+) -> FeedHandle {
     let (sender, mut quit_recv) = oneshot::channel();
+    feed.limit_fetches(fetch_limit);
+    feed.space_requests(host_limit);
+    if settings.archive_only {
+        feed.archive_only();
+    }
+    feed.limit_notifications(settings.max_notifications_per_hour);
+    let paused = feed.pause_handle();
+    let stats = Arc::new(FeedStats::default());
+    let loop_stats = Arc::clone(&stats);
+    settings.normal_sleep = feed.interval().unwrap_or(settings.normal_sleep);
+    stats.record_interval(settings.normal_sleep);
+    let feed_dormant_after = feed.dormant_after().unwrap_or(settings.dormant_after);
+    let delay = startup_delay(
+        &feed.source(),
+        settings.normal_sleep,
+        settings.startup_jitter,
+    );
     tokio::spawn(async move {
+        // The feed was just fetched when it was loaded, so nothing is missed by waiting
+        tokio::select! {
+            _ = &mut quit_recv => {
+                trace!("Quit signal received for feed {}", redact_url(&feed.source()));
+                return;
+            }
+            _ = tokio::time::sleep(delay) => {}
+        }
+
+        let mut failures = 0;
         loop {
             tokio::select! {
                 _ = &mut quit_recv => {
-                    trace!("Quit signal received for feed {}", feed.source());
+                    trace!("Quit signal received for feed {}", redact_url(&feed.source()));
                     break;
                 }
 
-                _ = refresh_once(&tx, &*store, &mut feed, normal_sleep, fail_sleep) => {
+                running = refresh_once(&tx, &health, &store, &mut feed, &settings, &mut failures, &loop_stats) => {
+                    if !running {
+                        break;
+                    }
                 }
             }
         }
     });
-    sender
+    FeedHandle {
+        quit: sender,
+        paused,
+        stats,
+        started: Utc::now(),
+        dormant_after: feed_dormant_after,
+    }
+}
+
+/// Offset of up to `percent` of `interval` before a feed's first refresh. Derived from a SHA-256 of
+/// the URL so loops started together spread out, and each feed keeps its slot across restarts and
+/// Rust releases.
+fn startup_delay(source: &str, interval: Duration, percent: u8) -> Duration {
+    if percent == 0 {
+        return Duration::ZERO;
+    }
+    let digest = Sha256::digest(source.as_bytes());
+    let seed = u64::from_be_bytes(digest[..8].try_into().expect("digest is 32 bytes"));
+    let fraction = (seed % 10_000) as f64 / 10_000.0;
+    interval.mul_f64(fraction * f64::from(percent.min(100)) / 100.0)
 }
 
 async fn refresh_once(
     tx: &Sender<FeedEvent>,
+    health: &Sender<HealthEvent>,
     store: &SeenStore,
     feed: &mut RssFeed,
-    normal_sleep: Duration,
-    fail_sleep: Duration,
-) {
-    trace!("Starting to refresh feed {}", feed.source());
+    settings: &RefreshSettings,
+    failures: &mut u32,
+    stats: &FeedStats,
+) -> bool {
+    trace!("Starting to refresh feed {}", redact_url(&feed.source()));
     let start = tokio::time::Instant::now();
 
-    if let Err(e) = feed.refresh(store).await {
-        error!("Error refreshing {}: {:?}", feed.source(), e);
-        tokio::time::sleep(fail_sleep).await;
-        return;
+    let refreshed = feed
+        .refresh_into(store, settings.global_dedup, tx, settings.backpressure_warn)
+        .await;
+    let suppressed = feed.take_suppressed();
+    if suppressed > 0 {
+        let event = HealthEvent::RateLimited {
+            source: feed.source(),
+            suppressed,
+        };
+        if health.send(event).await.is_err() {
+            error!("Health channel closed, could not report rate limited feed");
+        }
     }
+    if let Err(e) = &refreshed {
+        let status = e.downcast_ref::<HttpStatusError>();
+        match status {
+            Some(status) => error!(
+                feed = redact_url(&feed.source());
+                "Error refreshing {}: {}",
+                redact_url(&feed.source()),
+                status
+            ),
+            None => error!(
+                feed = redact_url(&feed.source());
+                "Error refreshing {}: {:?}",
+                redact_url(&feed.source()),
+                e
+            ),
+        }
+        *failures += 1;
+        stats.record_failure(*failures);
+        if *failures == settings.failure_threshold {
+            let event = HealthEvent::Failing {
+                source: feed.source(),
+                failures: *failures,
+                // Request errors quote the URL, which can carry credentials
+                error: e
+                    .to_string()
+                    .replace(&feed.source(), &redact_url(&feed.source())),
+            };
+            // Health reports are best effort, the feed keeps its normal schedule without them
+            if health.send(event).await.is_err() {
+                error!("Health channel closed, could not report failing feed");
+            }
+        }
 
-    for item in feed.items() {
-        if tx
-            .send(FeedEvent {
+        // A 410 is final, server errors never count towards disabling since the feed still exists
+        let gone = matches!(status, Some(HttpStatusError::Gone(_)));
+        let transient = status.is_some_and(HttpStatusError::is_transient);
+        if settings.max_failures > 0 && (gone || (*failures >= settings.max_failures && !transient))
+        {
+            warn!(
+                feed = redact_url(&feed.source());
+                "Disabling feed {} after {} failures{}",
+                redact_url(&feed.source()),
+                failures,
+                if gone { ", it is gone" } else { "" }
+            );
+            store.set_feed_disabled(&feed.source(), true).await;
+            let event = HealthEvent::Disabled {
+                source: feed.source(),
+                failures: *failures,
+            };
+            let _ = health.send(event).await;
+            return false;
+        }
+
+        tokio::time::sleep(settings.fail_sleep).await;
+        return true;
+    }
+
+    if settings.failure_threshold > 0 && *failures >= settings.failure_threshold {
+        let event = HealthEvent::Recovered {
+            source: feed.source(),
+        };
+        if health.send(event).await.is_err() {
+            error!("Health channel closed, could not report recovered feed");
+        }
+    }
+    *failures = 0;
+    stats.record_success(feed.title());
+    store
+        .record_feed_success(&feed.source(), feed.title())
+        .await;
+
+    if settings.follow_moved
+        && let Some(to) = feed.moved_to().map(str::to_string)
+    {
+        let from = feed.source();
+        store.rename_feed(&from, &to).await;
+        feed.relocate(&to);
+        let _ = health.send(HealthEvent::Moved { from, to }).await;
+    }
+
+    if matches!(refreshed, Ok(false)) {
+        // The receiver only goes away with the manager, so there is no point in keeping any feed alive
+        error!(
+            feed = redact_url(&feed.source());
+            "Event queue closed, stopping refresh of {}",
+            redact_url(&feed.source())
+        );
+        let _ = health
+            .send(HealthEvent::QueueClosed {
                 source: feed.source(),
-                item,
             })
+            .await;
+        return false;
+    }
+
+    let elapsed = start.elapsed();
+    trace!(
+        "Feed {} took {:.3?} to refresh",
+        redact_url(&feed.source()),
+        elapsed
+    );
+    let sleep = poll_interval(feed, settings);
+    stats.record_interval(sleep);
+    if elapsed < sleep {
+        tokio::time::sleep(sleep - elapsed).await;
+    }
+    true
+}
+
+/// Time until the next refresh, stretched to the feed's `<ttl>` or `max-age` when it asks for
+/// longer than the configured interval and `honor_ttl` is set
+fn poll_interval(feed: &RssFeed, settings: &RefreshSettings) -> Duration {
+    match feed.advertised_ttl() {
+        Some(ttl) if settings.honor_ttl && ttl > settings.normal_sleep => {
+            debug!(
+                "Feed {} asks to be polled every {:?}",
+                redact_url(&feed.source()),
+                ttl
+            );
+            ttl
+        }
+        _ => settings.normal_sleep,
+    }
+}
+
+/// Queues an event, logging when the queue stays full for longer than `warn_after`.
+/// Returns false if the queue was closed.
+pub async fn send_event(tx: &Sender<FeedEvent>, event: FeedEvent, warn_after: Duration) -> bool {
+    if warn_after.is_zero() {
+        return tx.send(event).await.is_ok();
+    }
+
+    let permit = match tokio::time::timeout(warn_after, tx.reserve()).await {
+        Ok(permit) => permit,
+        Err(_) => {
+            warn!(
+                feed = redact_url(&event.source);
+                "Feed {} blocked for {:.1?} on a full event queue ({} events), delivery is falling behind",
+                redact_url(&event.source),
+                warn_after,
+                tx.max_capacity()
+            );
+            let start = tokio::time::Instant::now();
+            let permit = tx.reserve().await;
+            if permit.is_ok() {
+                info!(
+                    feed = redact_url(&event.source);
+                    "Feed {} resumed queueing after {:.1?}",
+                    redact_url(&event.source),
+                    warn_after + start.elapsed()
+                );
+            }
+            permit
+        }
+    };
+
+    match permit {
+        Ok(permit) => {
+            permit.send(event);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn specs(urls: &[&str]) -> Vec<FeedSpec> {
+        urls.iter()
+            .map(|url| FeedSpec::from(url.to_string()))
+            .collect()
+    }
+
+    /// `file://` URL of a fixture, loaded by the test-only file branch of the fetcher
+    fn fixture_url(name: &str) -> String {
+        let path = format!("{}/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+        reqwest::Url::from_file_path(path).unwrap().to_string()
+    }
+
+    /// Loops sleep long enough that no refresh runs during a test, and archive without events
+    fn settings() -> RefreshSettings {
+        RefreshSettings {
+            normal_sleep: Duration::from_secs(3600),
+            fail_sleep: Duration::from_secs(3600),
+            cache_size: 300,
+            max_body_size: 0,
+            global_dedup: false,
+            failure_threshold: 0,
+            max_failures: 0,
+            backpressure_warn: Duration::ZERO,
+            follow_moved: false,
+            honor_ttl: false,
+            max_concurrent_fetches: 0,
+            startup_jitter: 100,
+            dormant_after: Duration::ZERO,
+            archive_only: true,
+            max_notifications_per_hour: 0,
+            min_host_interval: Duration::ZERO,
+        }
+    }
+
+    async fn manager(database_path: &str, feeds: &[FeedSpec]) -> RssManager {
+        manager_with(database_path, feeds, settings()).await
+    }
+
+    async fn manager_with(
+        database_path: &str,
+        feeds: &[FeedSpec],
+        settings: RefreshSettings,
+    ) -> RssManager {
+        let (health, _) = mpsc::channel(16);
+        let queue = QueueSettings {
+            size: 16,
+            high_water: 0,
+            policy: QueuePolicy::Block,
+        };
+        RssManager::new(database_path, feeds, queue, settings, health)
             .await
-            .is_err()
+            .unwrap()
+            .0
+    }
+
+    /// Refresh loops still running, each one holds a reference to the store
+    async fn running_loops(manager: &RssManager) -> usize {
+        // Stopped loops drop their reference once they have seen the quit signal
+        for _ in 0..100 {
+            tokio::task::yield_now().await;
+        }
+        Arc::strong_count(&manager.seen_store) - 1
+    }
+
+    #[tokio::test]
+    async fn re_adding_a_feed_keeps_one_refresh_loop() {
+        let url = fixture_url("basic.xml");
+        let mut manager = manager(":memory:", &specs(&[&url])).await;
+        assert_eq!(running_loops(&manager).await, 1);
+
+        assert!(!manager.add_feed(FeedSpec::from(url.clone())).await.unwrap());
+        assert_eq!(running_loops(&manager).await, 1);
+
+        // A loop replaced by a reloaded feed is told to quit
+        let feed = RssFeed::new(FeedSpec::from(url), 300, 0).await.unwrap();
+        manager.start_feed(feed).await;
+        assert_eq!(manager.len(), 1);
+        assert_eq!(running_loops(&manager).await, 1);
+    }
+
+    #[tokio::test]
+    async fn remove_tells_apart_running_stored_and_unknown_feeds() {
+        let running = fixture_url("basic.xml");
+        let retrying = fixture_url("missing.xml");
+        let mut manager = manager(":memory:", &specs(&[&running, &retrying])).await;
+        assert_eq!(manager.starting_feeds(), vec![retrying.as_str()]);
+        let stored = "https://stored.example/rss";
+        manager.store().push_feeds(vec![stored.to_string()]).await;
+
+        assert!(matches!(
+            manager.remove_feed(&running).await,
+            RemoveOutcome::Stopped
+        ));
+        assert_eq!(running_loops(&manager).await, 0);
+        assert!(matches!(
+            manager.remove_feed(&retrying).await,
+            RemoveOutcome::Stopped
+        ));
+        assert!(manager.starting_feeds().is_empty());
+        assert!(matches!(
+            manager.remove_feed(stored).await,
+            RemoveOutcome::DatabaseOnly
+        ));
+        assert!(manager.store().get_feeds().await.is_empty());
+        assert!(matches!(
+            manager.remove_feed(stored).await,
+            RemoveOutcome::NotFound
+        ));
+    }
+
+    #[tokio::test]
+    async fn feed_in_config_and_database_runs_once() {
+        let path = std::env::temp_dir().join(format!(
+            "korvatunturi-watcher-dedup-{}.db",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        std::fs::File::create(&path).unwrap();
+        let path = path.to_str().unwrap();
+        let url = fixture_url("basic.xml");
         {
-            return;
+            // Stored before URLs were canonicalized, the fragment is dropped on startup
+            let store = SeenStore::new(path).await.unwrap();
+            store.push_feeds(vec![format!("{}#top", url)]).await;
         }
+
+        let manager = manager(path, &specs(&[&url])).await;
+        assert_eq!(manager.feeds(), vec![url.as_str()]);
+        assert_eq!(running_loops(&manager).await, 1);
+        assert_eq!(manager.store().get_feeds().await, [url]);
+        drop(manager);
+        let _ = std::fs::remove_file(path);
     }
 
-    let elapsed = start.elapsed();
-    trace!("Feed {} took {:.3?} to refresh", feed.source(), elapsed);
-    if elapsed < normal_sleep {
-        tokio::time::sleep(normal_sleep - elapsed).await;
+    #[tokio::test]
+    async fn feed_options_apply_right_away_and_are_stored() {
+        let url = fixture_url("basic.xml");
+        let mut manager = manager(":memory:", &[]).await;
+        let options = vec![("interval".to_string(), "120".to_string())];
+        let spec = FeedSpec::with_options(url.clone(), &options).unwrap();
+
+        assert!(manager.follow_feed(spec.clone(), &options).await.unwrap());
+        let interval = manager.feed_list[&url]
+            .stats
+            .interval
+            .load(Ordering::Relaxed);
+        assert_eq!(interval, 120);
+        assert_eq!(manager.store().feed_options().await[&url], options);
+
+        // Following it again keeps what is stored
+        assert!(!manager.follow_feed(spec, &[]).await.unwrap());
+        assert_eq!(manager.store().feed_options().await[&url], options);
+    }
+
+    #[tokio::test]
+    async fn stored_options_come_back_after_a_restart() {
+        let path = std::env::temp_dir().join(format!(
+            "korvatunturi-watcher-options-{}.db",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        std::fs::File::create(&path).unwrap();
+        let path = path.to_str().unwrap();
+        let url = fixture_url("basic.xml");
+        let options = vec![
+            ("interval".to_string(), "120".to_string()),
+            ("dormant_after_days".to_string(), "3".to_string()),
+        ];
+        {
+            let mut manager = manager(path, &[]).await;
+            let spec = FeedSpec::with_options(url.clone(), &options).unwrap();
+            assert!(manager.follow_feed(spec, &options).await.unwrap());
+            // Written by hand, e.g. by an older release that accepted it
+            let broken = fixture_url("latin1.xml");
+            manager.store().push_feeds(vec![broken.clone()]).await;
+            let interval = vec![("interval".to_string(), "soon".to_string())];
+            manager.store().set_feed_options(&broken, &interval).await;
+        }
+
+        let manager = manager(path, &[]).await;
+        let handle = &manager.feed_list[&url];
+        assert_eq!(handle.stats.interval.load(Ordering::Relaxed), 120);
+        assert_eq!(handle.dormant_after, Duration::from_secs(3 * 24 * 60 * 60));
+        assert_eq!(manager.store().feed_options().await[&url], options);
+        // Options that no longer parse are dropped for the defaults instead of the feed
+        let broken = &manager.feed_list[&fixture_url("latin1.xml")];
+        assert_eq!(broken.stats.interval.load(Ordering::Relaxed), 3600);
+        drop(manager);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn disabled_feed_is_only_restarted_by_resume() {
+        let url = fixture_url("basic.xml");
+        let mut manager = manager(":memory:", &specs(&[&url])).await;
+        // What a refresh loop does once the feed failed too often
+        manager.store().set_feed_disabled(&url, true).await;
+        manager.mark_disabled(&url);
+        assert_eq!(running_loops(&manager).await, 0);
+
+        assert!(!manager.add_feed(FeedSpec::from(url.clone())).await.unwrap());
+        assert_eq!(running_loops(&manager).await, 0);
+        assert!(manager.is_disabled(&url));
+
+        assert!(
+            manager
+                .resume_feed(FeedSpec::from(url.clone()))
+                .await
+                .unwrap()
+        );
+        assert_eq!(running_loops(&manager).await, 1);
+        assert!(!manager.is_disabled(&url));
+        assert!(manager.store().get_disabled_feeds().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn pause_is_shared_with_the_loop_and_stored() {
+        let url = fixture_url("basic.xml");
+        let manager = manager(":memory:", &specs(&[&url])).await;
+        assert!(
+            !manager
+                .set_paused("https://unknown.example/rss", true)
+                .await
+        );
+
+        assert!(manager.set_paused(&url, true).await);
+        assert!(manager.is_paused(&url));
+        assert_eq!(manager.store().get_paused_feeds().await, [url.as_str()]);
+        assert_eq!(running_loops(&manager).await, 1);
+
+        assert!(manager.set_paused(&url, false).await);
+        assert!(!manager.is_paused(&url));
+        assert!(manager.store().get_paused_feeds().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn feeds_without_recent_items_are_dormant() {
+        let quiet = fixture_url("basic.xml");
+        let patient = fixture_url("latin1.xml");
+        let feeds = [
+            FeedSpec::from(quiet.clone()),
+            FeedSpec {
+                dormant_after_days: Some(3650),
+                ..FeedSpec::from(patient.clone())
+            },
+        ];
+        let settings = RefreshSettings {
+            dormant_after: Duration::from_secs(30 * 24 * 60 * 60),
+            ..settings()
+        };
+        let manager = manager_with(":memory:", &feeds, settings).await;
+        assert!(manager.dormant_feeds().await.is_empty());
+
+        let mut item = Item::default();
+        item.set_pub_date("Thu, 05 Jun 2025 09:00:00 GMT".to_string());
+        for feed in [&quiet, &patient] {
+            manager.store().mark_seen(&item, feed, feed, false).await;
+        }
+        let newest = DateTime::parse_from_rfc3339("2025-06-05T09:00:00Z").unwrap();
+        assert_eq!(manager.dormant_feeds().await, [(quiet, newest.to_utc())]);
+    }
+
+    #[tokio::test]
+    async fn resolve_splits_loaded_and_failed_feeds() {
+        let feeds = specs(&[
+            &fixture_url("basic.xml"),
+            &fixture_url("missing.xml"),
+            &fixture_url("latin1.xml"),
+            "ftp://example.com/rss",
+        ]);
+        let hosts = HostLimiter::new(Duration::ZERO);
+        let (loaded, failed) = resolve_feeds(feeds, 300, 0, 2, &hosts).await;
+
+        let titles: Vec<Option<&str>> = loaded.iter().map(|feed| feed.title()).collect();
+        assert_eq!(titles, [Some("Example Feed"), Some("Café Überblick")]);
+        let failed: Vec<&str> = failed.iter().map(|spec| spec.url.as_str()).collect();
+        assert_eq!(
+            failed,
+            [fixture_url("missing.xml").as_str(), "ftp://example.com/rss"]
+        );
+    }
+
+    #[tokio::test]
+    async fn ttl_stretches_the_poll_interval_unless_ignored() {
+        let path =
+            std::env::temp_dir().join(format!("korvatunturi-ttl-{}.xml", std::process::id()));
+        let body =
+            std::fs::read_to_string(format!("{}/fixtures/basic.xml", env!("CARGO_MANIFEST_DIR")))
+                .unwrap()
+                .replace("<channel>", "<channel>\n    <ttl>120</ttl>");
+        std::fs::write(&path, body).unwrap();
+        let url = reqwest::Url::from_file_path(&path).unwrap().to_string();
+        let feed = RssFeed::new(FeedSpec::from(url), 300, 0).await.unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(feed.advertised_ttl(), Some(Duration::from_secs(120 * 60)));
+
+        let mut settings = settings();
+        settings.honor_ttl = true;
+        assert_eq!(
+            poll_interval(&feed, &settings),
+            Duration::from_secs(120 * 60)
+        );
+        // A ttl below the configured interval never polls more often
+        settings.normal_sleep = Duration::from_secs(3 * 3600);
+        assert_eq!(
+            poll_interval(&feed, &settings),
+            Duration::from_secs(3 * 3600)
+        );
+
+        settings.normal_sleep = Duration::from_secs(900);
+        settings.honor_ttl = false;
+        assert_eq!(poll_interval(&feed, &settings), Duration::from_secs(900));
+    }
+
+    #[test]
+    fn diff_splits_added_and_removed_feeds() {
+        let current = vec![
+            "https://a.example/rss".to_string(),
+            "https://b.example/rss".to_string(),
+        ];
+        let desired = specs(&["https://b.example/rss", "https://c.example/rss"]);
+        let (added, removed) = diff_feeds(&current, &desired);
+        assert_eq!(added, specs(&["https://c.example/rss"]));
+        assert_eq!(removed, vec!["https://a.example/rss"]);
+    }
+
+    #[test]
+    fn startup_delay_is_fixed_per_url() {
+        let interval = Duration::from_secs(600);
+        let delay = startup_delay("https://a.example/rss", interval, 100);
+        assert_eq!(delay, startup_delay("https://a.example/rss", interval, 100));
+        // Pinned so a change of hash shows up as moved slots
+        assert_eq!((delay.as_secs_f64() * 1000.0).round(), 324_180.0);
+        assert_ne!(delay, startup_delay("https://b.example/rss", interval, 100));
+    }
+
+    #[test]
+    fn startup_delay_stays_within_its_share_of_the_interval() {
+        let interval = Duration::from_secs(600);
+        for i in 0..50 {
+            let url = format!("https://feed{}.example/rss", i);
+            assert!(startup_delay(&url, interval, 10) <= interval / 10);
+            assert_eq!(startup_delay(&url, interval, 0), Duration::ZERO);
+        }
+    }
+
+    #[test]
+    fn diff_of_equal_lists_is_empty() {
+        let desired = specs(&["https://a.example/rss"]);
+        let (added, removed) = diff_feeds(&["https://a.example/rss".to_string()], &desired);
+        assert!(added.is_empty() && removed.is_empty());
     }
 }
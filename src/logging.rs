@@ -0,0 +1,158 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use log::kv::{self, Key, Value, VisitSource};
+use serde_json::{Map, Value as JsonValue};
+
+/// Copies the key-values of a log record, such as `feed`, into a JSON log line
+pub struct JsonFields<'a>(pub &'a mut Map<String, JsonValue>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0
+            .insert(key.as_str().to_string(), JsonValue::from(value.to_string()));
+        Ok(())
+    }
+}
+
+/// Log file that rolls over to `<path>.1`, `<path>.2`, ... once it grows past `max_size` bytes
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    pub fn open(path: impl Into<PathBuf>, max_size: u64, max_files: usize) -> io::Result<Self> {
+        let path = path.into();
+        if let Some(parent) = path.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        // Shift older files up by one, the oldest falls off the end
+        if self.max_files > 0 {
+            for i in (1..self.max_files).rev() {
+                let from = self.rotated_path(i);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(i + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("korvatunturi-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn rotates_past_max_size_and_prunes_old_files() {
+        let dir = scratch_dir("rotate");
+        let path = dir.join("daemon.log");
+        let mut log = RotatingFile::open(&path, 10, 2).unwrap();
+        for line in ["first 1\n", "second\n", "third\n", "fourth\n", "fifth\n"] {
+            log.write_all(line.as_bytes()).unwrap();
+        }
+        log.flush().unwrap();
+
+        let rotated = |i: usize| dir.join(format!("daemon.log.{}", i));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fifth\n");
+        assert_eq!(fs::read_to_string(rotated(1)).unwrap(), "fourth\n");
+        assert_eq!(fs::read_to_string(rotated(2)).unwrap(), "third\n");
+        assert!(!rotated(3).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reopening_keeps_counting_the_existing_size() {
+        let dir = scratch_dir("reopen");
+        let path = dir.join("daemon.log");
+        RotatingFile::open(&path, 10, 1)
+            .unwrap()
+            .write_all(b"12345678")
+            .unwrap();
+
+        let mut log = RotatingFile::open(&path, 10, 1).unwrap();
+        log.write_all(b"abc").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "abc");
+        assert_eq!(
+            fs::read_to_string(dir.join("daemon.log.1")).unwrap(),
+            "12345678"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn copies_record_context_into_json_fields() {
+        let context = [("feed", "https://example.com/rss")];
+        let record = log::Record::builder()
+            .args(format_args!("Error refreshing"))
+            .key_values(&context)
+            .build();
+        let mut fields = Map::new();
+        record
+            .key_values()
+            .visit(&mut JsonFields(&mut fields))
+            .unwrap();
+        assert_eq!(fields["feed"], "https://example.com/rss");
+    }
+}
@@ -3,55 +3,223 @@ mod cli;
 mod config;
 mod db;
 mod feeds;
+mod logging;
+mod pidfile;
 mod server;
+mod systemd;
 use clap::Parser;
 use colored::Colorize;
-use env_logger;
-use log::error;
+use log::{error, info};
+use std::io::{IsTerminal, Write};
 
 use crate::server::ServerCommand;
 
-fn init_logging(v: u8) {
+fn init_logging(v: u8, json: bool, file: Option<logging::RotatingFile>) {
     let filter = match v {
         0 => "info",
         1 => "debug",
         _ => "trace",
     };
 
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(filter)).init();
+    let mut builder =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(filter));
+    if json {
+        // One JSON object per line for log aggregators
+        builder.format(|buf, record| {
+            let mut line = serde_json::json!({
+                "timestamp": chrono::Utc::now().to_rfc3339(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            // Context attached to the record, e.g. the feed a message is about
+            if let Some(fields) = line.as_object_mut() {
+                let _ = record.key_values().visit(&mut logging::JsonFields(fields));
+            }
+            writeln!(buf, "{}", line)
+        });
+    }
+    if let Some(file) = file {
+        builder.target(env_logger::Target::Pipe(Box::new(file)));
+    }
+    builder.init();
+}
+
+/// Colors are off with --no-color, a non-empty NO_COLOR, or when stdout is not a terminal unless
+/// CLICOLOR_FORCE asks for them anyway
+fn use_color(no_color: bool) -> bool {
+    let set = |var| std::env::var_os(var).is_some_and(|v| !v.is_empty());
+    if no_color || set("NO_COLOR") {
+        return false;
+    }
+    std::io::stdout().is_terminal() || set("CLICOLOR_FORCE")
+}
+
+/// Resolves on Ctrl-C, or SIGTERM on unix
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = term.recv() => {}
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
 }
 
 #[tokio::main]
 async fn main() {
     let args = args::Args::parse();
+    if !use_color(args.no_color) {
+        colored::control::set_override(false);
+    }
+
+    if args.init {
+        let path = args.output.as_deref().unwrap_or(&args.config);
+        match config::write_default_config(path, args.force) {
+            Ok(()) => println!("{} {}", "Wrote default config to".green().bold(), path),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                eprintln!(
+                    "{} {} already exists, pass --force to overwrite it",
+                    "Error:".red().bold(),
+                    path
+                );
+                std::process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("{} could not write {}: {}", "Error:".red().bold(), path, e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
 
     // Config
     let cfg = match config::load_config(&args.config) {
         Ok(c) => c,
         Err(e) => {
             eprintln!("Failed to load config {}: {}", args.config, e);
+            if args.validate {
+                std::process::exit(1);
+            }
             return;
         }
     };
 
+    if args.print_config {
+        match serde_json::to_string_pretty(&cfg) {
+            Ok(json) => println!("{}", json),
+            Err(e) => {
+                eprintln!(
+                    "{} could not serialize config: {}",
+                    "Error:".red().bold(),
+                    e
+                );
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if args.validate {
+        if cli::report_config_issues(&cfg.validate()) {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let http = feeds::feed::HttpSettings {
+        max_redirects: cfg.feeds.max_redirects,
+        proxy: cfg.proxy.clone(),
+        no_proxy: cfg.no_proxy.clone(),
+    };
+    if let Err(e) = feeds::feed::configure_http(&http) {
+        eprintln!("Failed to build HTTP client: {}", e);
+        std::process::exit(1);
+    }
+
     if args.check {
-        cli::check_feeds(cfg.feeds.get(), args.verbose).await;
+        let feeds = if args.stdin {
+            cli::read_feed_list(std::io::stdin().lock())
+        } else {
+            cfg.feeds.get()
+        };
+        if feeds.is_empty() {
+            eprintln!("{} no feeds to check", "Error:".red().bold());
+            std::process::exit(1);
+        }
+        cli::check_feeds(
+            feeds,
+            cfg.feeds.item_cache_size,
+            cfg.feeds.max_body_size,
+            cfg.feeds.max_concurrent_fetches,
+            std::time::Duration::from_secs(cfg.feeds.min_host_interval),
+            args.verbose,
+        )
+        .await;
         return;
     }
 
     if args.daemon {
-        init_logging(args.verbose);
-        if let Err(e) = server::start(cfg).await {
-            error!("Starting daemon failed: {:?}", e);
+        let log_file = cfg.logging.file.as_ref().and_then(|path| {
+            logging::RotatingFile::open(path, cfg.logging.max_size, cfg.logging.max_files)
+                .inspect_err(|e| eprintln!("Failed to open log file {}: {}", path, e))
+                .ok()
+        });
+        init_logging(args.verbose, args.log_json || cfg.logging.json, log_file);
+        // Held until the daemon returns so the file is removed on shutdown
+        let _pidfile = match args.pidfile.as_deref().map(pidfile::PidFile::create) {
+            Some(Err(e)) => {
+                error!("Could not create pid file: {}", e);
+                std::process::exit(1);
+            }
+            Some(Ok(p)) => Some(p),
+            None => None,
+        };
+        tokio::select! {
+            res = server::start(cfg, args.dry_run) => {
+                if let Err(e) = res {
+                    error!("Starting daemon failed: {:?}", e);
+                }
+            }
+            _ = shutdown_signal() => {
+                info!("Shutting down");
+                systemd::notify_stopping();
+            }
+        }
+        return;
+    }
+
+    if args.repl {
+        if let Err(e) = cli::repl(cfg, args.json).await {
+            eprintln!("{} {}", "Error:".red().bold(), e);
+            std::process::exit(1);
         }
         return;
     }
 
     if !args.cli.is_empty() {
-        let arg = args.cli.join(" ");
-        match ServerCommand::try_from(arg) {
+        match ServerCommand::try_from(cli::command_line(&args.cli)) {
+            Ok(ServerCommand::ForgetFeed(..)) if !args.yes => {
+                eprintln!(
+                    "{} feed forget deletes the feed's history, pass --yes to confirm",
+                    "Error:".red().bold()
+                );
+                std::process::exit(1);
+            }
             Ok(cmd) => {
-                if let Err(e) = cli::send_command(cfg, cmd).await {
+                if let Err(e) = cli::send_command(cfg, cmd, args.json).await {
                     eprintln!("{} {:?}", "Sending command failed:".red().bold(), e);
                 }
             }
@@ -0,0 +1,98 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+};
+
+/// PID file owned by the running daemon, removed again when dropped
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Writes the current PID to `path`. Fails with `AlreadyExists` if the file names a live process,
+    /// a file left behind by a dead process is replaced.
+    pub fn create(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        if let Ok(existing) = fs::read_to_string(&path) {
+            if let Ok(pid) = existing.trim().parse::<u32>()
+                && is_running(pid)
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("already running with pid {}", pid),
+                ));
+            }
+            fs::remove_file(&path)?;
+        }
+
+        if let Some(parent) = path.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Self { path })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // Signal 0 only checks that the process exists. EPERM means it exists but belongs to another
+    // user, which still has to count as running.
+    // SAFETY: kill with signal 0 sends nothing and touches no memory
+    let signalled = unsafe { libc::kill(pid, 0) } == 0;
+    signalled || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_running(pid: u32) -> bool {
+    std::process::Command::new("tasklist")
+        .args(["/NH", "/FI", &format!("PID eq {}", pid)])
+        .output()
+        .is_ok_and(|o| String::from_utf8_lossy(&o.stdout).contains(&pid.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn processes_of_other_users_count_as_running() {
+        assert!(is_running(std::process::id()));
+        // init is owned by root, tests running unprivileged get EPERM for it
+        assert!(is_running(1));
+    }
+
+    #[test]
+    fn replaces_a_file_left_by_a_dead_process() {
+        let path =
+            std::env::temp_dir().join(format!("korvatunturi-pidfile-{}.pid", std::process::id()));
+        fs::write(&path, format!("{}\n", i32::MAX)).unwrap();
+        let pidfile = PidFile::create(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap().trim(),
+            std::process::id().to_string()
+        );
+
+        let err = PidFile::create(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        drop(pidfile);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,318 @@
+use std::{io, sync::Arc, time::Duration};
+
+use log::{debug, error, info, warn};
+use reqwest::Url;
+use serde_json::Value;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot,
+    },
+};
+
+use crate::config::redact_url;
+use crate::server::{
+    commands::{CommandMessage, DEFAULT_RECENT, MAX_RECENT, ServerCommand, json_reply},
+    server::token_matches,
+};
+
+/// Longest request line and headers read before a request is rejected
+const MAX_REQUEST: u64 = 8 * 1024;
+/// Time a client has to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serves the read-only JSON API on `addr`. Each route is answered by the same read-only command
+/// the socket interface runs, so nothing can be changed through it. On a non-loopback address
+/// requests have to carry `auth_token` as a bearer token, without one the API is not started.
+pub async fn serve(
+    addr: &str,
+    commands: mpsc::Sender<CommandMessage>,
+    auth_token: Option<Arc<str>>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    let local = listener.local_addr()?;
+    // Feed URLs and item links are still worth keeping from anyone who can reach the port
+    let required_token = if local.ip().is_loopback() {
+        None
+    } else {
+        let Some(token) = auth_token else {
+            return Err(io::Error::other(format!(
+                "refusing to serve the HTTP API on non-loopback address {} without an auth_token",
+                local
+            )));
+        };
+        warn!("HTTP API is reachable on non-loopback address {}", local);
+        Some(token)
+    };
+
+    info!("Serving the HTTP API on http://{}", local);
+    tokio::spawn(async move {
+        loop {
+            let conn = match listener.accept().await {
+                Ok((c, peer)) => {
+                    debug!("Accepted API connection from {}", peer);
+                    c
+                }
+                Err(e) => {
+                    error!("Error accepting API connection: {}", e);
+                    continue;
+                }
+            };
+            tokio::spawn(handle_request(
+                conn,
+                commands.clone(),
+                required_token.clone(),
+            ));
+        }
+    });
+    Ok(())
+}
+
+async fn handle_request(
+    conn: TcpStream,
+    commands: mpsc::Sender<CommandMessage>,
+    required_token: Option<Arc<str>>,
+) {
+    let (reader, mut writer) = conn.into_split();
+    let mut reader = BufReader::new(reader.take(MAX_REQUEST));
+    let request = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut reader)).await {
+        Ok(Ok(request)) => request,
+        Ok(Err(e)) => {
+            debug!("Rejected API request: {}", e);
+            let _ = respond(&mut writer, 400, "Bad Request", &error_body(&e.to_string())).await;
+            return;
+        }
+        Err(_elapsed) => return,
+    };
+
+    if let Some(expected) = required_token.as_deref() {
+        let authorized = request
+            .bearer
+            .as_deref()
+            .is_some_and(|token| token_matches(token, expected));
+        if !authorized {
+            warn!("Rejected API request with a missing or wrong bearer token");
+            let _ = respond(
+                &mut writer,
+                401,
+                "Unauthorized",
+                &error_body("unauthorized"),
+            )
+            .await;
+            return;
+        }
+    }
+
+    let (code, reason, body) = match request.target {
+        Target::Get(path) => match route(&path) {
+            Some(cmd) => run(cmd, &commands).await,
+            None => (404, "Not Found", error_body("unknown route")),
+        },
+        Target::Other(method) => (
+            405,
+            "Method Not Allowed",
+            error_body(&format!("{} is not allowed, the API is read-only", method)),
+        ),
+    };
+    if let Err(e) = respond(&mut writer, code, reason, &body).await {
+        error!("Failed to send API response: {}", e);
+    }
+}
+
+enum Target {
+    Get(String),
+    Other(String),
+}
+
+struct Request {
+    target: Target,
+    /// Token from an `Authorization: Bearer` header
+    bearer: Option<String>,
+}
+
+/// Reads the request line and the headers, requests never carry a body
+async fn read_request<R>(reader: &mut R) -> io::Result<Request>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "malformed request line",
+        ));
+    };
+    let target = match method {
+        "GET" => Target::Get(target.to_string()),
+        other => Target::Other(other.to_string()),
+    };
+
+    let mut bearer = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request headers too long or cut off",
+            ));
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            return Ok(Request { target, bearer });
+        }
+        if let Some((name, value)) = header.split_once(':')
+            && name.trim().eq_ignore_ascii_case("authorization")
+            && let Some(token) = value.trim().strip_prefix("Bearer ")
+        {
+            bearer = Some(token.trim().to_string());
+        }
+    }
+}
+
+/// Maps a request path to the read-only command answering it
+fn route(path: &str) -> Option<ServerCommand> {
+    // Only used to split and decode the path and query
+    let url = Url::parse(&format!("http://api{}", path)).ok()?;
+    match url.path() {
+        "/feeds" => Some(ServerCommand::GetFeeds),
+        "/status" => Some(ServerCommand::Status),
+        "/items" => {
+            let mut feed = None;
+            let mut limit = DEFAULT_RECENT;
+            for (key, value) in url.query_pairs() {
+                match key.as_ref() {
+                    "feed" if !value.is_empty() => feed = Some(value.into_owned()),
+                    "limit" => limit = value.parse().unwrap_or(DEFAULT_RECENT),
+                    _ => {}
+                }
+            }
+            Some(ServerCommand::Recent(feed, limit.min(MAX_RECENT)))
+        }
+        _ => None,
+    }
+}
+
+/// Runs `cmd` through the server loop and returns the status line and JSON body to answer with
+async fn run(
+    cmd: ServerCommand,
+    commands: &mpsc::Sender<CommandMessage>,
+) -> (u16, &'static str, String) {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let message = CommandMessage {
+        cmd,
+        reply: reply_tx,
+        json: true,
+    };
+    match commands.try_send(message) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => {
+            return (503, "Service Unavailable", error_body("busy, try again"));
+        }
+        Err(TrySendError::Closed(_)) => {
+            return (500, "Internal Server Error", error_body("internal"));
+        }
+    }
+    match reply_rx.await {
+        Ok(reply) if reply.starts_with("ACK") => (200, "OK", redacted_reply(&reply)),
+        Ok(reply) => (500, "Internal Server Error", redacted_reply(&reply)),
+        Err(_canceled) => (500, "Internal Server Error", error_body("no-reply")),
+    }
+}
+
+/// JSON body for `reply` with credentials and secret query parameters removed from every URL,
+/// since feed and item URLs can carry tokens the socket interface shows in full
+fn redacted_reply(reply: &str) -> String {
+    let mut body: Value = serde_json::from_str(&json_reply(reply)).unwrap_or(Value::Null);
+    redact_strings(&mut body);
+    body.to_string()
+}
+
+fn redact_strings(value: &mut Value) {
+    match value {
+        Value::String(s) => *s = redact_url(s),
+        Value::Array(values) => values.iter_mut().for_each(redact_strings),
+        Value::Object(fields) => fields.values_mut().for_each(redact_strings),
+        _ => {}
+    }
+}
+
+fn error_body(message: &str) -> String {
+    json_reply(&format!("ERR {}", message))
+}
+
+async fn respond<W>(writer: &mut W, code: u16, reason: &str, body: &str) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        code,
+        reason,
+        body.len()
+    );
+    writer.write_all(head.as_bytes()).await?;
+    writer.write_all(body.as_bytes()).await?;
+    writer.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_items_with_feed_and_clamped_limit() {
+        match route("/items?feed=https%3A%2F%2Fexample.com%2Frss&limit=100000") {
+            Some(ServerCommand::Recent(Some(feed), limit)) => {
+                assert_eq!(feed, "https://example.com/rss");
+                assert_eq!(limit, MAX_RECENT);
+            }
+            other => panic!("unexpected route {:?}", other.map(|c| c.to_string())),
+        }
+        assert!(matches!(
+            route("/items?limit=x"),
+            Some(ServerCommand::Recent(None, DEFAULT_RECENT))
+        ));
+        assert!(route("/feeds/../admin").is_none());
+    }
+
+    #[tokio::test]
+    async fn reads_bearer_token_from_headers() {
+        let raw = b"GET /status HTTP/1.1\r\nHost: x\r\nauthorization: Bearer secret \r\n\r\n";
+        let request = read_request(&mut &raw[..]).await.unwrap();
+        assert!(matches!(request.target, Target::Get(ref path) if path == "/status"));
+        assert_eq!(request.bearer.as_deref(), Some("secret"));
+
+        let raw = b"POST /feeds HTTP/1.1\r\n\r\n";
+        let request = read_request(&mut &raw[..]).await.unwrap();
+        assert!(matches!(request.target, Target::Other(ref method) if method == "POST"));
+        assert!(request.bearer.is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_cut_off_headers() {
+        let raw = b"GET /feeds HTTP/1.1\r\nHost: x\r\n";
+        assert!(read_request(&mut &raw[..]).await.is_err());
+    }
+
+    #[test]
+    fn redacts_urls_in_replies() {
+        let reply =
+            r#"ACK [{"url":"https://user:pw@example.com/rss?token=abc","title":"Plain title"}]"#;
+        let body: Value = serde_json::from_str(&redacted_reply(reply)).unwrap();
+        let feed = &body["data"][0];
+        assert_eq!(feed["title"], "Plain title");
+        let url = feed["url"].as_str().unwrap();
+        assert!(!url.contains("pw") && !url.contains("abc"), "{}", url);
+    }
+
+    #[tokio::test]
+    async fn refuses_non_loopback_without_token() {
+        let (tx, _rx) = mpsc::channel(1);
+        let err = serve("0.0.0.0:0", tx, None).await.unwrap_err();
+        assert!(err.to_string().contains("auth_token"));
+    }
+}
@@ -1,22 +1,151 @@
-use std::fmt::Display;
+use std::{fmt::Display, io};
 
-use tokio::sync::oneshot;
+use interprocess::local_socket::{GenericFilePath, GenericNamespaced, Name, prelude::*};
+use serde_json::{Value, json};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::oneshot,
+};
 pub static VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const DEFAULT_RECENT: usize = 10;
+pub const MAX_RECENT: usize = 50;
+pub const SEARCH_LIMIT: u32 = 20;
+/// Prefix of a command line asking for a JSON reply, e.g. `json status`
+pub const JSON_MARKER: &str = "json";
+/// Starts a frame header line, `#<length>`, followed by exactly that many bytes of message
+pub const FRAME_MARKER: char = '#';
+/// Largest frame a peer may announce, and the longest plain line or frame header accepted
+const MAX_FRAME: usize = 1024 * 1024;
+
+/// Writes `message` as one length-prefixed frame, so it may contain newlines
+pub async fn write_frame<W>(writer: &mut W, message: &str) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let header = format!("{}{}\n", FRAME_MARKER, message.len());
+    writer.write_all(header.as_bytes()).await?;
+    writer.write_all(message.as_bytes()).await
+}
+
+/// Reads one message and whether it was framed, `None` once the peer closed the connection.
+/// Anything that is not a frame header is taken as a plain line, which keeps simple line based
+/// clients such as `nc` working.
+///
+/// Both ends of the command protocol go through here and `write_frame`: the client sends the
+/// optional auth token and then the command as frames, and the daemon answers with one frame.
+/// Plain line requests get a plain reply that ends when the daemon closes the connection.
+pub async fn read_message<R>(reader: &mut R) -> io::Result<Option<(String, bool)>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = String::new();
+    if (&mut *reader)
+        .take(MAX_FRAME as u64 + 1)
+        .read_line(&mut line)
+        .await?
+        == 0
+    {
+        return Ok(None);
+    }
+    if line.len() > MAX_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("line is longer than {} bytes", MAX_FRAME),
+        ));
+    }
+    let line = line.trim_end_matches(['\r', '\n']);
+    let Some(len) = line
+        .strip_prefix(FRAME_MARKER)
+        .and_then(|len| len.parse::<usize>().ok())
+    else {
+        return Ok(Some((line.to_string(), false)));
+    };
+    if len > MAX_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes is larger than {}", len, MAX_FRAME),
+        ));
+    }
+
+    let mut message = vec![0; len];
+    reader.read_exact(&mut message).await?;
+    String::from_utf8(message)
+        .map(|m| Some((m, true)))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Maps the configured socket to a local socket name. Values containing a path separator are
+/// filesystem paths, anything else is a namespaced name: abstract on Linux, `/tmp/<name>` on other
+/// unices and a named pipe on Windows.
+pub fn local_socket_name(socket: &str) -> io::Result<Name<'_>> {
+    if socket.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "socket name is empty",
+        ));
+    }
+    if socket.contains('/') || socket.contains('\\') {
+        socket.to_fs_name::<GenericFilePath>()
+    } else {
+        socket.to_ns_name::<GenericNamespaced>()
+    }
+}
 
 pub enum ServerCommand {
-    AddFeed(String),
+    /// Feed URLs and the `key=value` options applied to each of them
+    AddFeed(Vec<String>, Vec<(String, String)>),
     RemoveFeed(String),
+    ResumeFeed(String),
+    PauseFeed(String),
+    FeedStats,
+    /// Feed url and whether the archive is kept
+    ForgetFeed(String, bool),
+    /// Archived item id, guid or link
+    Resend(String),
+    Vacuum,
+    Search(String),
     GetFeeds,
+    Status,
+    Reload,
     Ping,
     Version,
+    Help,
+    TestWebhook(Option<String>),
+    Recent(Option<String>, usize),
 }
 
+static HELP: &str = "Supported commands:
+  feed add <url> [url...] [key=value...]
+                           Start following one or more feeds, with options such as interval=120
+  feed remove <url>        Stop following a feed
+  feed pause <url>         Keep archiving a feed's items without notifying them
+  feed resume <url>        Resume a paused feed or revive one disabled after too many failures
+  feed stats               Show how many items each feed has archived and when it last had one
+  feed forget <url> [keep-archive]
+                           Clear a stopped feed's seen items so they are notified again
+  resend <id|guid|link>    Send an archived item's notification again
+  vacuum                   Compact the database file and report the reclaimed space
+  list                     List followed feeds with their title, last refresh and failures
+  recent [url] [n]         Show the last n archived items, optionally for one feed
+  search <terms>           Search archived item titles and descriptions
+  stats                    Same as feed stats
+  status                   Show active, stale, dormant, disabled and retrying feeds
+  reload                   Re-read the feed list from the config
+  test-webhook [feed url]  Send a sample notification to the global or the feed's webhook
+  ping                     Check that the daemon is alive and show its uptime
+  version                  Show the daemon version
+  help                     Show this message
+
+Arguments with spaces can be quoted, e.g. search \"rust async\"";
+
 #[derive(Debug)]
 pub enum CommandParseError {
     MissingKeyword,
     UnknownKeyword,
     NotLongEnough,
     MissingLink,
+    InvalidNumber,
+    UnclosedQuote,
 }
 
 impl Display for CommandParseError {
@@ -26,6 +155,8 @@ impl Display for CommandParseError {
             CommandParseError::UnknownKeyword => "Unknown keyword",
             CommandParseError::NotLongEnough => "Command not long enough",
             CommandParseError::MissingLink => "Missing link",
+            CommandParseError::InvalidNumber => "Invalid number",
+            CommandParseError::UnclosedQuote => "Unclosed quote",
         };
         write!(f, "{}", text)
     }
@@ -34,64 +165,413 @@ impl Display for CommandParseError {
 pub struct CommandMessage {
     pub cmd: ServerCommand,
     pub reply: oneshot::Sender<String>,
+    /// The client asked for a JSON reply. Handlers with structured output reply with a JSON
+    /// document after the `ACK`, everything else is wrapped by `json_reply`.
+    pub json: bool,
+}
+
+/// Splits the JSON marker off a command line
+pub fn strip_json_marker(line: &str) -> (bool, &str) {
+    match line.split_once(' ') {
+        Some((marker, rest)) if marker.eq_ignore_ascii_case(JSON_MARKER) => (true, rest.trim()),
+        _ => (false, line),
+    }
+}
+
+/// Turns an `ACK ...`/`ERR ...` reply into `{"status": "ok"|"error", "data": ...}`. Data that is
+/// already a JSON object or array is embedded as is, any other text becomes a string.
+pub fn json_reply(reply: &str) -> String {
+    let reply = reply.trim_end();
+    let (status, rest) = match reply.split_once(' ') {
+        Some(("ACK", rest)) => ("ok", rest),
+        Some(("ERR", rest)) => ("error", rest),
+        _ if reply == "ACK" => ("ok", ""),
+        _ => ("error", reply),
+    };
+    let data = match serde_json::from_str::<Value>(rest) {
+        Ok(value) if value.is_object() || value.is_array() => value,
+        _ => Value::String(rest.to_string()),
+    };
+    json!({ "status": status, "data": data }).to_string()
+}
+
+/// Splits a command line on whitespace. Single or double quotes keep an argument with spaces
+/// together, e.g. `search "rust async"`, and a backslash takes the next character literally.
+fn tokenize(line: &str) -> Result<Vec<String>, CommandParseError> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    // Tracked apart from `current` so `""` still yields an empty argument
+    let mut in_token = false;
+    let mut quote = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            ('\\', q) if q != Some('\'') => {
+                current.extend(chars.next());
+                in_token = true;
+            }
+            (c, Some(q)) if c == q => quote = None,
+            (_, Some(_)) => current.push(c),
+            ('"' | '\'', None) => {
+                quote = Some(c);
+                in_token = true;
+            }
+            (c, None) if c.is_whitespace() => {
+                if in_token {
+                    tokens.push(std::mem::take(&mut current));
+                    in_token = false;
+                }
+            }
+            (c, None) => {
+                current.push(c);
+                in_token = true;
+            }
+        }
+    }
+    if quote.is_some() {
+        return Err(CommandParseError::UnclosedQuote);
+    }
+    if in_token {
+        tokens.push(current);
+    }
+    Ok(tokens)
+}
+
+/// Splits a `key=value` feed option. URLs never match since their scheme comes before any `=`.
+fn feed_option(arg: &str) -> Option<(&str, &str)> {
+    let (key, value) = arg.split_once('=')?;
+    let is_key = !key.is_empty() && key.chars().all(|c| c.is_ascii_lowercase() || c == '_');
+    is_key.then_some((key, value))
+}
+
+/// Quotes an argument for `tokenize` when it would otherwise be split or unescaped
+pub fn quote_arg(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || "\"'\\".contains(c)) {
+        return arg.to_string();
+    }
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
 }
 
 impl TryFrom<String> for ServerCommand {
     type Error = CommandParseError;
 
     fn try_from(cmd: String) -> Result<Self, Self::Error> {
-        let mut cmd_iter = cmd.split(" ").into_iter();
+        let tokens = tokenize(&cmd)?;
+        let mut cmd_iter = tokens.iter().map(String::as_str);
         if let Some(w) = cmd_iter.next() {
-            let command = match w {
-                "feed" => match cmd_iter.next() {
-                    Some("add") => ServerCommand::AddFeed(
+            // Keywords are case-insensitive, arguments such as URLs are passed through untouched
+            let command = match w.to_lowercase().as_str() {
+                "feed" => match cmd_iter.next().map(|s| s.to_lowercase()).as_deref() {
+                    Some("add") => {
+                        let (mut feeds, mut options) = (Vec::new(), Vec::new());
+                        for arg in cmd_iter.filter(|s| !s.is_empty()) {
+                            match feed_option(arg) {
+                                Some((key, value)) => {
+                                    options.push((key.to_string(), value.to_string()))
+                                }
+                                None => feeds.push(arg.to_string()),
+                            }
+                        }
+                        if feeds.is_empty() {
+                            return Err(CommandParseError::MissingLink);
+                        }
+                        ServerCommand::AddFeed(feeds, options)
+                    }
+                    Some("remove") => ServerCommand::RemoveFeed(
                         cmd_iter
                             .next()
                             .ok_or(CommandParseError::MissingLink)?
                             .to_string(),
                     ),
-                    Some("remove") => ServerCommand::RemoveFeed(
+                    Some("resume") => ServerCommand::ResumeFeed(
                         cmd_iter
                             .next()
                             .ok_or(CommandParseError::MissingLink)?
                             .to_string(),
                     ),
+                    Some("pause") => ServerCommand::PauseFeed(
+                        cmd_iter
+                            .next()
+                            .ok_or(CommandParseError::MissingLink)?
+                            .to_string(),
+                    ),
+                    Some("stats") => ServerCommand::FeedStats,
+                    Some("forget") => {
+                        let feed = cmd_iter
+                            .next()
+                            .ok_or(CommandParseError::MissingLink)?
+                            .to_string();
+                        let keep_archive =
+                            match cmd_iter.next().map(|s| s.to_lowercase()).as_deref() {
+                                Some("keep-archive") => true,
+                                Some(_) => return Err(CommandParseError::UnknownKeyword),
+                                None => false,
+                            };
+                        ServerCommand::ForgetFeed(feed, keep_archive)
+                    }
                     Some(_) => return Err(CommandParseError::UnknownKeyword),
                     None => return Err(CommandParseError::NotLongEnough),
                 },
+                "stats" => ServerCommand::FeedStats,
                 "ping" => ServerCommand::Ping,
                 "version" => ServerCommand::Version,
                 "list" => ServerCommand::GetFeeds,
+                "reload" => ServerCommand::Reload,
+                "status" => ServerCommand::Status,
+                "help" => ServerCommand::Help,
+                "vacuum" => ServerCommand::Vacuum,
+                "search" => {
+                    let terms: Vec<&str> = cmd_iter.filter(|s| !s.is_empty()).collect();
+                    if terms.is_empty() {
+                        return Err(CommandParseError::NotLongEnough);
+                    }
+                    ServerCommand::Search(terms.join(" "))
+                }
+                "recent" => {
+                    let mut feed = None;
+                    let mut count = DEFAULT_RECENT;
+                    for arg in cmd_iter.filter(|s| !s.is_empty()) {
+                        match arg.parse::<usize>() {
+                            Ok(n) => count = n,
+                            Err(_) if feed.is_none() => feed = Some(arg.to_string()),
+                            Err(_) => return Err(CommandParseError::InvalidNumber),
+                        }
+                    }
+                    ServerCommand::Recent(feed, count.min(MAX_RECENT))
+                }
+                "resend" => ServerCommand::Resend(
+                    cmd_iter
+                        .next()
+                        .filter(|s| !s.is_empty())
+                        .ok_or(CommandParseError::NotLongEnough)?
+                        .to_string(),
+                ),
+                "test-webhook" => {
+                    ServerCommand::TestWebhook(cmd_iter.next().map(|s| s.to_string()))
+                }
                 _ => return Err(CommandParseError::MissingKeyword),
             };
             return Ok(command);
         }
-        return Err(CommandParseError::NotLongEnough);
+        Err(CommandParseError::NotLongEnough)
     }
 }
 
-impl ServerCommand {
-    pub fn to_string(self) -> String {
-        match self {
-            ServerCommand::AddFeed(feed) => {
-                format!("feed add {}", feed)
+impl Display for ServerCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            ServerCommand::AddFeed(feeds, options) => {
+                let args: Vec<String> = feeds
+                    .iter()
+                    .map(|feed| quote_arg(feed))
+                    .chain(
+                        options
+                            .iter()
+                            .map(|(k, v)| quote_arg(&format!("{}={}", k, v))),
+                    )
+                    .collect();
+                format!("feed add {}", args.join(" "))
             }
             ServerCommand::RemoveFeed(feed) => {
-                format!("feed remove {}", feed)
+                format!("feed remove {}", quote_arg(feed))
+            }
+            ServerCommand::ResumeFeed(feed) => {
+                format!("feed resume {}", quote_arg(feed))
+            }
+            ServerCommand::PauseFeed(feed) => {
+                format!("feed pause {}", quote_arg(feed))
             }
+            ServerCommand::FeedStats => "feed stats".to_string(),
+            ServerCommand::Resend(key) => format!("resend {}", quote_arg(key)),
+            ServerCommand::Vacuum => "vacuum".to_string(),
+            ServerCommand::Search(query) => format!("search {}", quote_arg(query)),
+            ServerCommand::ForgetFeed(feed, true) => {
+                format!("feed forget {} keep-archive", quote_arg(feed))
+            }
+            ServerCommand::ForgetFeed(feed, false) => format!("feed forget {}", quote_arg(feed)),
+            ServerCommand::Status => "status".to_string(),
             ServerCommand::Ping => "ping".to_string(),
             ServerCommand::Version => "version".to_string(),
             ServerCommand::GetFeeds => "list".to_string(),
-        }
+            ServerCommand::Reload => "reload".to_string(),
+            ServerCommand::Help => "help".to_string(),
+            ServerCommand::TestWebhook(Some(feed)) => format!("test-webhook {}", quote_arg(feed)),
+            ServerCommand::TestWebhook(None) => "test-webhook".to_string(),
+            ServerCommand::Recent(Some(feed), n) => format!("recent {} {}", quote_arg(feed), n),
+            ServerCommand::Recent(None, n) => format!("recent {}", n),
+        };
+        write!(f, "{}", text)
     }
+}
 
+impl ServerCommand {
     pub fn format_reply(&self) -> Option<String> {
         match &self {
-            ServerCommand::AddFeed(_) => None,
+            ServerCommand::AddFeed(_, _) => None,
             ServerCommand::RemoveFeed(_) => None,
+            ServerCommand::ResumeFeed(_) => None,
+            ServerCommand::PauseFeed(_) => None,
+            ServerCommand::FeedStats => None,
+            ServerCommand::Resend(_) => None,
+            ServerCommand::Vacuum => None,
+            ServerCommand::Search(_) => None,
+            ServerCommand::ForgetFeed(_, _) => None,
             ServerCommand::GetFeeds => None,
-            ServerCommand::Ping => Some("Pong".to_string()),
+            ServerCommand::Status => None,
+            ServerCommand::Reload => None,
+            ServerCommand::TestWebhook(_) => None,
+            ServerCommand::Recent(_, _) => None,
+            ServerCommand::Ping => None,
             ServerCommand::Version => Some(VERSION.to_string()),
+            ServerCommand::Help => Some(HELP.to_string()),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokenizes_quoted_and_unquoted_arguments() {
+        assert_eq!(
+            tokenize(r#"search  "rust async"  it\'s 'a "b"' "" x\ y"#).unwrap(),
+            ["search", "rust async", "it's", r#"a "b""#, "", "x y"]
+        );
+        assert_eq!(tokenize("  ").unwrap(), Vec::<String>::new());
+        assert!(matches!(
+            tokenize(r#"search "open"#),
+            Err(CommandParseError::UnclosedQuote)
+        ));
+    }
+
+    #[test]
+    fn display_parses_back_to_the_same_command() {
+        for cmd in [
+            ServerCommand::AddFeed(
+                vec![
+                    "https://example.com/rss?a=1&b=2".into(),
+                    "https://example.com/with space".into(),
+                ],
+                vec![("include".into(), r#"say "hi" \o/"#.into())],
+            ),
+            ServerCommand::Search("it's \"quoted\"".into()),
+            ServerCommand::ForgetFeed("https://example.com/rss".into(), true),
+            ServerCommand::Recent(Some("https://example.com/rss".into()), 5),
+            ServerCommand::Recent(None, 5),
+            ServerCommand::TestWebhook(None),
+        ] {
+            let line = cmd.to_string();
+            let parsed =
+                ServerCommand::try_from(line.clone()).unwrap_or_else(|e| panic!("{}: {}", line, e));
+            assert_eq!(parsed.to_string(), line);
+        }
+    }
+
+    #[test]
+    fn keywords_are_case_insensitive_but_arguments_are_not() {
+        let cmd = ServerCommand::try_from("FEED Remove https://Example.com/RSS".to_string());
+        assert!(
+            matches!(cmd, Ok(ServerCommand::RemoveFeed(ref url)) if url == "https://Example.com/RSS")
+        );
+        assert!(matches!(
+            ServerCommand::try_from("feed add interval=60".to_string()),
+            Err(CommandParseError::MissingLink)
+        ));
+    }
+
+    #[test]
+    fn help_lists_every_command() {
+        assert!(matches!(
+            ServerCommand::try_from("HeLp".to_string()),
+            Ok(ServerCommand::Help)
+        ));
+        let help = ServerCommand::Help.format_reply().unwrap();
+
+        let url = || "https://example.com/rss".to_string();
+        for cmd in [
+            ServerCommand::AddFeed(vec![url()], Vec::new()),
+            ServerCommand::RemoveFeed(url()),
+            ServerCommand::ResumeFeed(url()),
+            ServerCommand::PauseFeed(url()),
+            ServerCommand::FeedStats,
+            ServerCommand::ForgetFeed(url(), false),
+            ServerCommand::Resend("id".into()),
+            ServerCommand::Vacuum,
+            ServerCommand::Search("rust".into()),
+            ServerCommand::GetFeeds,
+            ServerCommand::Status,
+            ServerCommand::Reload,
+            ServerCommand::Ping,
+            ServerCommand::Version,
+            ServerCommand::Help,
+            ServerCommand::TestWebhook(None),
+            ServerCommand::Recent(None, 5),
+        ] {
+            let line = cmd.to_string();
+            // The keyword, with the sub-command for feed commands
+            let words = if line.starts_with("feed ") { 2 } else { 1 };
+            let keyword: Vec<&str> = line.split(' ').take(words).collect();
+            let keyword = keyword.join(" ");
+            assert!(
+                help.lines().any(|l| l.trim_start().starts_with(&keyword)),
+                "{} is missing from help",
+                keyword
+            );
+        }
+    }
+
+    #[test]
+    fn json_replies_embed_documents_and_wrap_text() {
+        let status = json!({
+            "active": 2,
+            "queue": { "depth": 0, "capacity": 8 },
+            "paused": ["https://example.com/rss"],
+        });
+        let reply: Value = serde_json::from_str(&json_reply(&format!("ACK {}\n", status))).unwrap();
+        assert_eq!(reply, json!({ "status": "ok", "data": status }));
+
+        let search: Value = serde_json::from_str(&json_reply("ACK []\n")).unwrap();
+        assert_eq!(search, json!({ "status": "ok", "data": [] }));
+
+        // Plain text stays a string, including text that is only a JSON scalar
+        let text: Value = serde_json::from_str(&json_reply("ACK 42\n")).unwrap();
+        assert_eq!(text, json!({ "status": "ok", "data": "42" }));
+        let err: Value = serde_json::from_str(&json_reply("ERR busy\n")).unwrap();
+        assert_eq!(err, json!({ "status": "error", "data": "busy" }));
+        let (json, line) = strip_json_marker("JSON search rust");
+        assert!(json);
+        assert_eq!(line, "search rust");
+    }
+
+    #[tokio::test]
+    async fn long_commands_are_read_up_to_the_frame_limit() {
+        let command = format!(
+            "feed add https://example.com/rss?q={}",
+            "a".repeat(64 * 1024)
+        );
+        let mut framed = Vec::new();
+        write_frame(&mut framed, &command).await.unwrap();
+        let mut reader = &framed[..];
+        assert_eq!(
+            read_message(&mut reader).await.unwrap(),
+            Some((command.clone(), true))
+        );
+        assert_eq!(read_message(&mut reader).await.unwrap(), None);
+
+        let line = format!("{}\n", command);
+        let mut reader = line.as_bytes();
+        assert_eq!(
+            read_message(&mut reader).await.unwrap(),
+            Some((command, false))
+        );
+
+        // Rejected from the header alone, before any of the body is read
+        let header = format!("#{}\n", MAX_FRAME + 1);
+        let err = read_message(&mut header.as_bytes()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let line = "a".repeat(MAX_FRAME + 1);
+        let err = read_message(&mut line.as_bytes()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,670 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::Arc,
+    time::Duration,
+};
+
+use hmac::{Hmac, Mac};
+use log::{debug, error, info, warn};
+use reqwest::{Client, RequestBuilder, StatusCode, header::CONTENT_TYPE};
+use rss::Item;
+use serde_json::{Value, json};
+use sha2::Sha256;
+use tokio::sync::mpsc::{self, Sender, error::TrySendError};
+
+use crate::{
+    config::{Delivery, Embed, EmbedColor, Mastodon, OverflowPolicy, Push, PushKind, redact_url},
+    db::SeenStore,
+    feeds::watcher::FeedEvent,
+};
+
+/// Embed for one item, `feed_name` is shown as its author
+pub fn build_payload(
+    feed_name: &str,
+    title: &str,
+    description: &str,
+    link: &str,
+    embed: &Embed,
+    color: EmbedColor,
+) -> Value {
+    json!({
+        "content": embed.content,
+        "tts": false,
+        "embeds": [
+            {
+                "author": { "name": feed_name.chars().take(256).collect::<String>() },
+                "title": title,
+                "description": description,
+                "url": link,
+                "color": color.0
+            }
+        ]
+    })
+}
+
+/// Most fields Discord accepts in one embed
+const DIGEST_MAX_FIELDS: usize = 25;
+
+/// One embed listing each item's title and link, items past Discord's field limit are only counted
+fn build_digest_payload(
+    summary: &str,
+    source: &str,
+    items: &[&Item],
+    embed: &Embed,
+    color: EmbedColor,
+) -> Value {
+    let fields: Vec<Value> = items
+        .iter()
+        .take(DIGEST_MAX_FIELDS)
+        .map(|item| {
+            let title = item.title.as_deref().unwrap_or("<title not specified>");
+            let link = item.link.as_deref().unwrap_or("<link not specified>");
+            json!({
+                "name": title.chars().take(256).collect::<String>(),
+                "value": link.chars().take(1024).collect::<String>()
+            })
+        })
+        .collect();
+    let mut digest = json!({
+        "title": summary.chars().take(256).collect::<String>(),
+        "url": source,
+        "color": color.0,
+        "fields": fields
+    });
+    if items.len() > DIGEST_MAX_FIELDS {
+        digest["footer"] =
+            json!({ "text": format!("and {} more", items.len() - DIGEST_MAX_FIELDS) });
+    }
+    json!({
+        "content": embed.content,
+        "tts": false,
+        "embeds": [digest]
+    })
+}
+
+/// Channel title of the event's feed, or its URL without credentials for feeds that have none
+fn feed_name(event: &FeedEvent) -> String {
+    event
+        .feed_title
+        .clone()
+        .unwrap_or_else(|| redact_url(&event.source))
+}
+
+/// `X-Signature` value for a request body: `sha256=` and the hex HMAC-SHA256, as GitHub signs
+/// its webhooks
+pub fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Webhook POST of `payload`, signed over the exact body bytes when a secret is set
+pub fn webhook_request(
+    client: &Client,
+    url: &str,
+    payload: &Value,
+    secret: Option<&str>,
+) -> RequestBuilder {
+    let body = payload.to_string();
+    let mut request = client.post(url).header(CONTENT_TYPE, "application/json");
+    if let Some(secret) = secret {
+        request = request.header("X-Signature", signature(secret, body.as_bytes()));
+    }
+    request.body(body)
+}
+
+/// Longest status most Mastodon instances accept
+const MASTODON_MAX_CHARS: usize = 500;
+
+/// Status text with the title cut short so the link always fits in `MASTODON_MAX_CHARS`
+fn mastodon_status(title: &str, link: &str) -> String {
+    let room = MASTODON_MAX_CHARS.saturating_sub(link.chars().count() + 2);
+    let title = if title.chars().count() > room {
+        let cut: String = title.chars().take(room.saturating_sub(1)).collect();
+        format!("{}…", cut.trim_end())
+    } else {
+        title.to_string()
+    };
+    let status = format!("{}\n\n{}", title, link);
+    status.chars().take(MASTODON_MAX_CHARS).collect()
+}
+
+/// Returns whether the status was posted, or rejected as a duplicate of one that already was
+async fn post_mastodon(
+    event: &FeedEvent,
+    title: &str,
+    link: &str,
+    mastodon: &Mastodon,
+    ctx: &DeliveryContext,
+) -> bool {
+    let mut payload = json!({
+        "status": mastodon_status(title, link),
+        "visibility": mastodon.visibility,
+    });
+    if let Some(spoiler) = &mastodon.spoiler_text {
+        payload["spoiler_text"] = json!(spoiler);
+    }
+    if ctx.dry_run {
+        info!("Dry run, not posting to Mastodon: {}", payload);
+        return true;
+    }
+
+    let url = format!(
+        "{}/api/v1/statuses",
+        mastodon.instance.trim_end_matches('/')
+    );
+    let request = ctx
+        .client
+        .post(url)
+        .bearer_auth(&mastodon.token)
+        // Lets the instance drop a repeated post of the same item, e.g. after a restart
+        .header("Idempotency-Key", &event.id)
+        .json(&payload);
+    match request.send().await {
+        Ok(res) if res.status() == StatusCode::UNPROCESSABLE_ENTITY => {
+            info!("Mastodon rejected {} as a duplicate, skipping it", event.id);
+            true
+        }
+        Ok(res) if !res.status().is_success() => {
+            error!("Mastodon rejected status with {}", res.status());
+            false
+        }
+        Ok(_) => true,
+        Err(e) => {
+            error!("Error posting to Mastodon: {}", e.without_url());
+            false
+        }
+    }
+}
+
+/// Sends the title as a push notification that opens the link when tapped
+async fn post_push(title: &str, link: &str, push: &Push, ctx: &DeliveryContext) -> bool {
+    let request = match push.kind {
+        PushKind::Ntfy => {
+            let mut request = ctx
+                .client
+                .post(&push.url)
+                .header("Click", link)
+                .body(title.to_string());
+            if let Some(priority) = push.priority {
+                request = request.header("Priority", priority.to_string());
+            }
+            if !push.tags.is_empty() {
+                request = request.header("Tags", push.tags.join(","));
+            }
+            if let Some(token) = &push.token {
+                request = request.bearer_auth(token);
+            }
+            request
+        }
+        PushKind::Gotify => {
+            let payload = json!({
+                "title": title,
+                "message": link,
+                "priority": push.priority.unwrap_or(5),
+                "extras": { "client::notification": { "click": { "url": link } } },
+            });
+            let url = format!("{}/message", push.url.trim_end_matches('/'));
+            ctx.client
+                .post(url)
+                .header("X-Gotify-Key", push.token.as_deref().unwrap_or_default())
+                .json(&payload)
+        }
+    };
+    if ctx.dry_run {
+        info!("Dry run, not sending {:?} push for {}", push.kind, link);
+        return true;
+    }
+
+    match request.send().await {
+        Ok(res) if !res.status().is_success() => {
+            error!("Push server rejected notification with {}", res.status());
+            false
+        }
+        Ok(_) => true,
+        Err(e) => {
+            error!("Error sending push notification: {}", e.without_url());
+            false
+        }
+    }
+}
+
+/// Returns whether the event was handled and can be marked as delivered
+async fn handle_event(event: &FeedEvent, ctx: &DeliveryContext) -> bool {
+    let title = event
+        .item
+        .title
+        .as_deref()
+        .unwrap_or("<title not specified>");
+    let description = event
+        .item
+        .description
+        .as_deref()
+        .unwrap_or("<description not specified>");
+    let link = event.item.link.as_deref().unwrap_or("<link not specified>");
+
+    debug!(
+        "Event: [{}] {} => {}",
+        redact_url(&event.source),
+        title,
+        link
+    );
+    let mut delivered = true;
+    if let Some(url) = event.webhook.as_deref().or(ctx.webhook.as_deref()) {
+        let color = event.color.unwrap_or(ctx.embed.color);
+        let payload = build_payload(
+            &feed_name(event),
+            title,
+            description,
+            link,
+            &ctx.embed,
+            color,
+        );
+        delivered &= send_webhook(url, &payload, ctx).await;
+    }
+
+    // Posted even when the webhook failed. The item is re-emitted on the next start and the repeat
+    // post is then rejected as a duplicate.
+    if let Some(mastodon) = &ctx.mastodon {
+        delivered &= post_mastodon(event, title, link, mastodon, ctx).await;
+    }
+    if let Some(push) = &ctx.push {
+        delivered &= post_push(title, link, push, ctx).await;
+    }
+    delivered
+}
+
+/// Sends a refresh's bundled items as one notification. Mastodon and push get a single summary
+/// that links the feed, a list of titles does not fit there.
+async fn handle_digest(event: &FeedEvent, ctx: &DeliveryContext) -> bool {
+    let items: Vec<&Item> = std::iter::once(&event.item)
+        .chain(event.digest.iter().map(|(_, item)| item))
+        .collect();
+    let source = redact_url(&event.source);
+    let summary = format!("{} new items from {}", items.len(), feed_name(event));
+
+    debug!("Digest: [{}] {} items", source, items.len());
+    let mut delivered = true;
+    if let Some(url) = event.webhook.as_deref().or(ctx.webhook.as_deref()) {
+        let color = event.color.unwrap_or(ctx.embed.color);
+        let payload = build_digest_payload(&summary, &source, &items, &ctx.embed, color);
+        delivered &= send_webhook(url, &payload, ctx).await;
+    }
+    if let Some(mastodon) = &ctx.mastodon {
+        delivered &= post_mastodon(event, &summary, &source, mastodon, ctx).await;
+    }
+    if let Some(push) = &ctx.push {
+        delivered &= post_push(&summary, &source, push, ctx).await;
+    }
+    delivered
+}
+
+/// Returns whether the webhook accepted the payload
+async fn send_webhook(url: &str, payload: &Value, ctx: &DeliveryContext) -> bool {
+    if ctx.dry_run {
+        info!("Dry run, not sending: {}", payload);
+        return true;
+    }
+
+    let request = webhook_request(&ctx.client, url, payload, ctx.webhook_secret.as_deref());
+    match request.send().await {
+        Ok(res) if !res.status().is_success() => {
+            error!("Webhook rejected item with status {}", res.status());
+            false
+        }
+        Ok(_) => true,
+        Err(e) => {
+            error!("Error sending item to webhook: {}", e.without_url());
+            false
+        }
+    }
+}
+
+/// Longest a health alert may take to post, a stuck error webhook must not hold up item delivery
+const ALERT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Posts a health alert as plain message content
+async fn send_alert(url: &str, message: &str, ctx: &DeliveryContext) {
+    let payload = json!({ "content": message });
+    if ctx.dry_run {
+        info!("Dry run, not sending health alert: {}", payload);
+        return;
+    }
+
+    let request = ctx.client.post(url).json(&payload).timeout(ALERT_TIMEOUT);
+    match request.send().await {
+        Ok(res) if !res.status().is_success() => {
+            error!(
+                "Error webhook rejected health alert with status {}",
+                res.status()
+            )
+        }
+        Ok(_) => {}
+        Err(e) => error!("Error sending health alert: {}", e.without_url()),
+    }
+}
+
+/// Work for a delivery worker
+enum Job {
+    Event(Box<FeedEvent>),
+    /// Health alert for the error webhook
+    Alert {
+        webhook: String,
+        message: String,
+    },
+}
+
+/// Everything a delivery worker needs to send and confirm an event
+pub struct DeliveryContext {
+    pub webhook: Option<String>,
+    pub webhook_secret: Option<String>,
+    pub embed: Embed,
+    pub client: Client,
+    pub store: Arc<SeenStore>,
+    pub mastodon: Option<Mastodon>,
+    pub push: Option<Push>,
+    /// Log payloads instead of posting them. Items are still archived and marked delivered.
+    pub dry_run: bool,
+}
+
+/// Hands events to a pool of delivery workers so slow webhooks do not hold up the server loop.
+/// Events for the same destination always go to the same worker, keeping them in order.
+pub struct Dispatcher {
+    workers: Vec<Sender<Job>>,
+    policy: OverflowPolicy,
+    webhook: Option<String>,
+}
+
+impl Dispatcher {
+    pub fn spawn(settings: &Delivery, ctx: DeliveryContext) -> Self {
+        let ctx = Arc::new(ctx);
+        let workers = (0..settings.workers.max(1))
+            .map(|_| {
+                let (send, mut recv) = mpsc::channel::<Job>(settings.queue.max(1));
+                let ctx = Arc::clone(&ctx);
+                tokio::spawn(async move {
+                    while let Some(job) = recv.recv().await {
+                        let event = match job {
+                            Job::Event(event) => *event,
+                            Job::Alert { webhook, message } => {
+                                send_alert(&webhook, &message, &ctx).await;
+                                continue;
+                            }
+                        };
+                        let handled = if event.digest.is_empty() {
+                            handle_event(&event, &ctx).await
+                        } else {
+                            handle_digest(&event, &ctx).await
+                        };
+                        // Undelivered items stay pending and are re-emitted on the next start,
+                        // one by one since the digest they were part of is gone
+                        if handled {
+                            ctx.store.mark_delivered(&event.id).await;
+                            for (id, _) in &event.digest {
+                                ctx.store.mark_delivered(id).await;
+                            }
+                        }
+                    }
+                });
+                send
+            })
+            .collect();
+
+        Self {
+            workers,
+            policy: settings.policy,
+            webhook: ctx.webhook.clone(),
+        }
+    }
+
+    pub async fn dispatch(&self, event: FeedEvent) {
+        let destination = event.webhook.as_deref().or(self.webhook.as_deref());
+        let worker = &self.workers[self.worker_for(destination)];
+        match self.policy {
+            OverflowPolicy::Block => {
+                if worker.send(Job::Event(Box::new(event))).await.is_err() {
+                    error!("Delivery worker stopped, event dropped");
+                }
+            }
+            OverflowPolicy::Drop => match worker.try_send(Job::Event(Box::new(event))) {
+                Ok(()) => {}
+                Err(TrySendError::Full(Job::Event(event))) => warn!(
+                    "Delivery queue full, dropping {} from {} until the next start",
+                    event.id,
+                    redact_url(&event.source)
+                ),
+                Err(TrySendError::Full(_)) => {}
+                Err(TrySendError::Closed(_)) => error!("Delivery worker stopped, event dropped"),
+            },
+        }
+    }
+
+    /// Queues a health alert for `webhook`. Alerts never wait for room, a full queue drops them so
+    /// the server loop keeps going.
+    pub fn alert(&self, webhook: &str, message: String) {
+        let worker = &self.workers[self.worker_for(Some(webhook))];
+        let job = Job::Alert {
+            webhook: webhook.to_string(),
+            message,
+        };
+        match worker.try_send(job) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => warn!("Delivery queue full, dropping health alert"),
+            Err(TrySendError::Closed(_)) => error!("Delivery worker stopped, health alert dropped"),
+        }
+    }
+
+    fn worker_for(&self, destination: Option<&str>) -> usize {
+        let mut hasher = DefaultHasher::new();
+        destination.hash(&mut hasher);
+        (hasher.finish() % self.workers.len() as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature_matches_the_rfc_4231_vector() {
+        assert_eq!(
+            signature("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn webhook_request_signs_the_body_it_sends() {
+        let payload = serde_json::json!({"content": "Weekly Rust news"});
+        let request = webhook_request(
+            &Client::new(),
+            "https://hooks.example/x",
+            &payload,
+            Some("secret"),
+        )
+        .build()
+        .unwrap();
+        let body = request.body().and_then(|b| b.as_bytes()).unwrap();
+        assert_eq!(
+            request.headers()["X-Signature"],
+            signature("secret", body).as_str()
+        );
+
+        let unsigned = webhook_request(&Client::new(), "https://hooks.example/x", &payload, None)
+            .build()
+            .unwrap();
+        assert!(!unsigned.headers().contains_key("X-Signature"));
+    }
+
+    #[test]
+    fn payload_carries_the_feed_color() {
+        let color: EmbedColor = serde_json::from_value(json!("#e67e22")).unwrap();
+        let payload = build_payload(
+            "Example Feed",
+            "Weekly Rust news",
+            "",
+            "https://example.com/posts/weekly",
+            &Embed::default(),
+            color,
+        );
+        assert_eq!(payload["embeds"][0]["color"], 0xe67e22);
+        assert_eq!(payload["embeds"][0]["author"]["name"], "Example Feed");
+    }
+
+    #[test]
+    fn colors_above_24_bits_are_rejected() {
+        for raw in [json!(0x1000000), json!("#1000000")] {
+            let err = serde_json::from_value::<EmbedColor>(raw.clone()).unwrap_err();
+            assert!(err.to_string().contains("24 bits"), "{}: {}", raw, err);
+        }
+        assert_eq!(
+            serde_json::from_value::<EmbedColor>(json!(0xFFFFFF)).unwrap(),
+            EmbedColor(0xFFFFFF)
+        );
+        assert!(serde_json::from_value::<EmbedColor>(json!("#zzzzzz")).is_err());
+    }
+
+    #[test]
+    fn digest_lists_items_and_counts_the_rest() {
+        let items: Vec<Item> = (1..=30)
+            .map(|n| {
+                let mut item = Item::default();
+                item.set_title(format!("Post {}", n));
+                item.set_link(format!("https://example.com/posts/{}", n));
+                item
+            })
+            .collect();
+        let refs: Vec<&Item> = items.iter().collect();
+        let digest = |items: &[&Item]| {
+            build_digest_payload(
+                "New items from Example Feed",
+                "https://example.com/feed.xml",
+                items,
+                &Embed::default(),
+                EmbedColor(0x5865f2),
+            )
+        };
+
+        let payload = digest(&refs[..3]);
+        let embed = &payload["embeds"][0];
+        assert_eq!(embed["title"], "New items from Example Feed");
+        assert_eq!(embed["url"], "https://example.com/feed.xml");
+        assert_eq!(
+            embed["fields"],
+            json!([
+                { "name": "Post 1", "value": "https://example.com/posts/1" },
+                { "name": "Post 2", "value": "https://example.com/posts/2" },
+                { "name": "Post 3", "value": "https://example.com/posts/3" },
+            ])
+        );
+        assert!(embed.get("footer").is_none());
+
+        let payload = digest(&refs);
+        let embed = &payload["embeds"][0];
+        assert_eq!(embed["fields"].as_array().unwrap().len(), DIGEST_MAX_FIELDS);
+        assert_eq!(embed["fields"][24]["name"], "Post 25");
+        assert_eq!(embed["footer"]["text"], "and 5 more");
+    }
+
+    /// Answers every request on a local port with `status` and returns its base URL
+    async fn status_server(status: &'static str) -> String {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((conn, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut conn = BufReader::new(conn);
+                    let mut length = 0;
+                    loop {
+                        let mut header = String::new();
+                        if conn.read_line(&mut header).await.unwrap() == 0 || header == "\r\n" {
+                            break;
+                        }
+                        if let Some((name, value)) = header.split_once(':')
+                            && name.eq_ignore_ascii_case("content-length")
+                        {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                    // Read so closing the connection does not reset it under the response
+                    let mut body = vec![0; length];
+                    conn.read_exact(&mut body).await.unwrap();
+                    let reply = format!(
+                        "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        status
+                    );
+                    let conn = conn.get_mut();
+                    conn.write_all(reply.as_bytes()).await.unwrap();
+                    let _ = conn.shutdown().await;
+                });
+            }
+        });
+        base
+    }
+
+    #[test]
+    fn mastodon_status_shortens_the_title_to_keep_the_link() {
+        let link = "https://example.com/posts/weekly";
+        assert_eq!(
+            mastodon_status("Weekly Rust news", link),
+            format!("Weekly Rust news\n\n{}", link)
+        );
+
+        let status = mastodon_status(&"Rust ".repeat(200), link);
+        assert!(status.chars().count() <= MASTODON_MAX_CHARS);
+        assert!(status.ends_with(&format!("…\n\n{}", link)), "{}", status);
+    }
+
+    #[tokio::test]
+    async fn mastodon_duplicates_count_as_delivered() {
+        let ctx = DeliveryContext {
+            webhook: None,
+            webhook_secret: None,
+            embed: Embed::default(),
+            client: Client::new(),
+            store: Arc::new(SeenStore::new(":memory:").await.unwrap()),
+            mastodon: None,
+            push: None,
+            dry_run: false,
+        };
+        let event = FeedEvent {
+            id: "post-3".to_string(),
+            source: "https://example.com/feed.xml".to_string(),
+            feed_title: None,
+            item: Item::default(),
+            webhook: None,
+            color: None,
+            digest: Vec::new(),
+        };
+
+        for (status, delivered) in [
+            ("200 OK", true),
+            ("422 Unprocessable Entity", true),
+            ("500 Internal Server Error", false),
+        ] {
+            let mastodon = Mastodon {
+                instance: status_server(status).await,
+                token: "token".to_string(),
+                visibility: Default::default(),
+                spoiler_text: None,
+            };
+            assert_eq!(
+                post_mastodon(
+                    &event,
+                    "Weekly Rust news",
+                    "https://example.com/posts/weekly",
+                    &mastodon,
+                    &ctx
+                )
+                .await,
+                delivered,
+                "{}",
+                status
+            );
+        }
+    }
+}
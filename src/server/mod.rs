@@ -1,7 +1,12 @@
+mod api;
 mod commands;
+mod delivery;
+#[allow(clippy::module_inception)]
 mod server;
 
-pub use commands::ServerCommand;
+pub use commands::{
+    JSON_MARKER, ServerCommand, VERSION, local_socket_name, quote_arg, read_message, write_frame,
+};
 pub use server::start;
 
 #[macro_export]
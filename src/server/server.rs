@@ -1,100 +1,329 @@
 use crate::{
-    config::AppConfig,
-    feeds::watcher::{FeedEvent, RssManager},
+    config::{AppConfig, EmbedColor, FeedSpec, canonical_url, redact_url},
+    db::{ArchivedItem, SeenStore},
+    feeds::{
+        feed::http_client,
+        watcher::{
+            FeedEvent, HealthEvent, QueueSettings, RefreshSettings, RemoveOutcome, RssManager,
+            diff_feeds, stored_spec,
+        },
+    },
     reply_err, reply_ok,
-    server::commands::{CommandMessage, ServerCommand},
+    server::{
+        api,
+        commands::{
+            CommandMessage, SEARCH_LIMIT, ServerCommand, json_reply, local_socket_name,
+            read_message, strip_json_marker, write_frame,
+        },
+        delivery::{DeliveryContext, Dispatcher, build_payload, webhook_request},
+    },
+    systemd,
+};
+use std::{
+    collections::HashSet,
+    sync::Arc,
+    time::{Duration, Instant},
 };
-use std::time::Duration;
 
 use log::{debug, error, info, warn};
-use reqwest::Client;
-use serde_json::json;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
 use tokio::{
     select,
     sync::{
-        mpsc::{self},
+        mpsc::{self, error::TrySendError},
         oneshot,
     },
 };
 use {
     interprocess::local_socket::{
-        GenericNamespaced, ListenerOptions,
+        ListenerOptions,
         tokio::{Stream, prelude::*},
     },
-    tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    tokio::{
+        io::{AsyncBufRead, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    },
 };
 
-// For now this is just using discord. This is mainly a placeholder function
-async fn handle_event(event: FeedEvent, webhook: Option<&str>, client: &Client) {
-    let title = event
-        .item
-        .title
-        .as_deref()
-        .unwrap_or("<title not specified>");
-    let description = event
-        .item
-        .description
-        .as_deref()
-        .unwrap_or("<description not specified>");
-    let link = event.item.link.as_deref().unwrap_or("<link not specified>");
-
-    debug!("Event: [{}] {} => {}", event.source, title, link);
-    if let Some(url) = webhook {
-        let payload = json!({
-            "content": "",
-            "tts": false,
-            "embeds": [
-                {
-                    "title": title,
-                    "description": description,
-                    "url": link,
-                    "color": 4963212
-                }
-            ]
-        });
+/// How often feeds are checked for having gone dormant
+const DORMANT_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Options a feed runs with: its config entry if it has one, else the options it was added with
+async fn feed_spec(cfg: &AppConfig, store: &SeenStore, url: String) -> FeedSpec {
+    match cfg.feeds.get().into_iter().find(|s| s.url == url) {
+        Some(spec) => spec,
+        None => {
+            let stored = store.feed_options().await.remove(&url).unwrap_or_default();
+            stored_spec(url, &stored)
+        }
+    }
+}
+
+/// Webhook and embed color used for `feed`, or the global ones without a feed
+async fn resolve_webhook(
+    cfg: &AppConfig,
+    store: &SeenStore,
+    feed: Option<&str>,
+) -> Result<(String, EmbedColor), String> {
+    let (webhook, color) = match feed {
+        Some(url) => {
+            let url = canonical_url(url);
+            let known = cfg.feeds.get().iter().any(|s| s.url == url)
+                || store.feed_records().await.contains_key(&url);
+            if !known {
+                return Err(format!("Feed {} is not followed", redact_url(&url)));
+            }
+            let spec = feed_spec(cfg, store, url).await;
+            (
+                spec.webhook.or_else(|| cfg.webhook.clone()),
+                spec.color.unwrap_or(cfg.embed.color),
+            )
+        }
+        None => (cfg.webhook.clone(), cfg.embed.color),
+    };
+    let webhook = webhook.ok_or_else(|| "No webhook configured".to_string())?;
+    Ok((webhook, color))
+}
 
-        if let Err(e) = client.post(url).json(&payload).send().await {
-            error!("Error sending alert: {}", e);
+/// Logs a health event and returns the alert posted about it to the error webhook
+fn health_message(event: HealthEvent) -> String {
+    match event {
+        HealthEvent::Failing {
+            source,
+            failures,
+            error,
+        } => {
+            let source = redact_url(&source);
+            warn!(
+                "Feed {} failed {} times in a row: {}",
+                source, failures, error
+            );
+            format!(
+                "Feed {} is failing after {} consecutive errors: {}",
+                source, failures, error
+            )
+        }
+        HealthEvent::Recovered { source } => {
+            let source = redact_url(&source);
+            info!("Feed {} recovered", source);
+            format!("Feed {} recovered", source)
+        }
+        HealthEvent::Disabled { source, failures } => {
+            let source = redact_url(&source);
+            warn!("Feed {} disabled after {} failures", source, failures);
+            format!(
+                "Feed {} was disabled after {} consecutive errors, use `feed resume` to revive it",
+                source, failures
+            )
+        }
+        HealthEvent::Moved { from, to } => {
+            let (from, to) = (redact_url(&from), redact_url(&to));
+            warn!("Feed {} moved to {}", from, to);
+            format!(
+                "Feed {} redirects to {} and is now followed there, update the config to match",
+                from, to
+            )
         }
+        HealthEvent::Dormant { source, newest } => {
+            let source = redact_url(&source);
+            warn!("Feed {} has not published since {}", source, newest);
+            format!(
+                "Feed {} still answers but has not published anything since {}",
+                source,
+                newest.format("%Y-%m-%d")
+            )
+        }
+        HealthEvent::RateLimited { source, suppressed } => {
+            let source = redact_url(&source);
+            warn!(
+                "Feed {} is rate limited, suppressed {} notifications",
+                source, suppressed
+            );
+            format!(
+                "Feed {} hit its notification limit, {} new items were archived without a notification",
+                source, suppressed
+            )
+        }
+        HealthEvent::QueueClosed { source } => {
+            let source = redact_url(&source);
+            error!("Event queue closed under feed {}, shutting down", source);
+            format!(
+                "Event queue closed under feed {}, the daemon is shutting down",
+                source
+            )
+        }
+    }
+}
+
+/// Renders an uptime as e.g. `2d 3h 4m 5s`, leading zero units are left out
+fn format_uptime(uptime: Duration) -> String {
+    let secs = uptime.as_secs();
+    let (days, hours, mins) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    let mut out = String::new();
+    if days > 0 {
+        out.push_str(&format!("{}d ", days));
     }
+    if days > 0 || hours > 0 {
+        out.push_str(&format!("{}h ", hours));
+    }
+    if secs >= 60 {
+        out.push_str(&format!("{}m ", mins));
+    }
+    out.push_str(&format!("{}s", secs % 60));
+    out
+}
+
+/// JSON form of an archived item in `recent` and `search` replies
+fn item_json(item: &ArchivedItem) -> Value {
+    json!({
+        "title": item.title,
+        "link": item.link,
+        "pub_date": item.pub_date,
+        "feed": item.feed_source,
+        "enclosure_url": item.enclosure_url,
+        "enclosure_type": item.enclosure_type,
+        "enclosure_length": item.enclosure_length,
+    })
 }
 
-pub async fn start(cfg: AppConfig) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+/// With `dry_run` nothing is posted to any webhook, payloads are logged instead
+pub async fn start(
+    cfg: AppConfig,
+    dry_run: bool,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     info!("Starting RSS watcher server");
+    // Checked before any feed is loaded so a bad name fails right away
+    if let Err(e) = local_socket_name(&cfg.socket) {
+        return Err(format!("Invalid socket name {}: {}", cfg.socket, e).into());
+    }
+    if dry_run {
+        warn!("Dry run, notifications are logged instead of sent");
+    }
+    let started = Instant::now();
     let feeds = cfg.feeds.get();
+    let mut config_feeds: Vec<String> = feeds.iter().map(|f| f.url.clone()).collect();
+    let (health_send, mut health_recv) = mpsc::channel(cfg.feeds.queue);
     let (mut manager, failed_urls) = RssManager::new(
         &cfg.database.path,
         &feeds,
-        cfg.feeds.queue,
-        Duration::from_secs(cfg.feeds.refresh_interval.try_into()?),
+        QueueSettings {
+            size: cfg.feeds.queue,
+            high_water: cfg.feeds.queue_high_water,
+            policy: cfg.feeds.queue_policy,
+        },
+        RefreshSettings {
+            normal_sleep: Duration::from_secs(cfg.feeds.refresh_interval.try_into()?),
+            fail_sleep: Duration::from_secs(cfg.feeds.fail_interval),
+            cache_size: cfg.feeds.item_cache_size,
+            max_body_size: cfg.feeds.max_body_size,
+            global_dedup: cfg.feeds.global_dedup,
+            failure_threshold: cfg.feeds.failure_threshold,
+            max_failures: cfg.feeds.max_failures,
+            backpressure_warn: Duration::from_secs(cfg.feeds.backpressure_warn),
+            follow_moved: cfg.feeds.follow_moved,
+            honor_ttl: cfg.feeds.honor_ttl,
+            max_concurrent_fetches: cfg.feeds.max_concurrent_fetches,
+            startup_jitter: cfg.feeds.startup_jitter,
+            dormant_after: Duration::from_secs(cfg.feeds.dormant_after_days * 24 * 60 * 60),
+            archive_only: cfg.delivery.archive_only,
+            max_notifications_per_hour: cfg.feeds.max_notifications_per_hour,
+            min_host_interval: Duration::from_secs(cfg.feeds.min_host_interval),
+        },
+        health_send,
     )
     .await?;
 
     info!("RssManager initialized with {} feeds", manager.len());
     if !failed_urls.is_empty() {
         warn!(
-            "{} feeds failed to initialize. Please run --check -vv to identify them",
-            failed_urls.len()
+            "{} feeds failed to initialize and are retried every {:?}. Please run --check -vv to identify them",
+            failed_urls.len(),
+            Duration::from_secs(cfg.feeds.fail_interval)
         );
     }
-    let mut command_recv = create_ipc_listener(&cfg.socket)?;
-    let client = Client::new();
+    let mut command_recv = create_ipc_listener(
+        &cfg.socket,
+        cfg.tcp.as_deref(),
+        cfg.auth_token.clone().map(Arc::from),
+        cfg.command_queue,
+        cfg.api_addr.as_deref(),
+    )
+    .await?;
+    let client = http_client().clone();
+    let dispatcher = Dispatcher::spawn(
+        &cfg.delivery,
+        DeliveryContext {
+            webhook: cfg.webhook.clone(),
+            webhook_secret: cfg.webhook_secret.clone(),
+            embed: cfg.embed.clone(),
+            client: client.clone(),
+            store: manager.shared_store(),
+            mastodon: cfg.mastodon.clone(),
+            push: cfg.push.clone(),
+            dry_run,
+        },
+    );
+    systemd::notify_ready();
+    let mut dormant_check = tokio::time::interval(DORMANT_CHECK_INTERVAL);
+    // Each dormant feed is reported once, until it publishes again
+    let mut reported_dormant = HashSet::new();
     loop {
         select! {
             maybe_event = manager.next() => {
                 if let Some(e) = maybe_event {
-                    handle_event(e, cfg.webhook.as_deref(), &client).await;
+                    dispatcher.dispatch(e).await;
+                }
+            }
+            maybe_health = health_recv.recv() => {
+                if let Some(h) = maybe_health {
+                    let fatal = matches!(h, HealthEvent::QueueClosed { .. });
+                    if let HealthEvent::Disabled { source, .. } = &h {
+                        manager.mark_disabled(source);
+                    }
+                    if let HealthEvent::Moved { from, to } = &h {
+                        manager.rename_feed(from, to);
+                    }
+                    let message = health_message(h);
+                    if let Some(url) = &cfg.error_webhook {
+                        dispatcher.alert(url, message);
+                    }
+                    if fatal {
+                        return Err("event queue closed".into());
+                    }
+                }
+            }
+            _ = dormant_check.tick() => {
+                let dormant = manager.dormant_feeds().await;
+                reported_dormant.retain(|url| dormant.iter().any(|(d, _)| d == url));
+                for (source, newest) in dormant {
+                    if reported_dormant.insert(source.clone()) {
+                        let message = health_message(HealthEvent::Dormant { source, newest });
+                        if let Some(url) = &cfg.error_webhook {
+                            dispatcher.alert(url, message);
+                        }
+                    }
                 }
             }
             cmd = command_recv.recv() => {
-                if let Some(CommandMessage { cmd, reply: tx }) = cmd {
+                if let Some(CommandMessage { cmd, reply: tx, json }) = cmd {
                     match cmd {
-                        ServerCommand::AddFeed(feed) => {
-                            match manager.add_feed(&feed).await {
-                                Ok(new) => {
-                                    let msg = if new { "Added" } else { "Did not add" };
-                                    reply_ok!(tx, "ACK {} feed: {}", msg, feed);
+                        ServerCommand::AddFeed(feeds, options) if feeds.len() == 1 => {
+                            let feed = &feeds[0];
+                            let spec = match FeedSpec::with_options(feed.clone(), &options) {
+                                Ok(spec) => spec,
+                                Err(e) => {
+                                    reply_err!(tx, "ERR Invalid feed option: {}", e);
+                                    continue;
+                                }
+                            };
+                            let url = spec.url.clone();
+                            match manager.follow_feed(spec, &options).await {
+                                Ok(true) => reply_ok!(tx, "ACK Added feed: {}", feed),
+                                Ok(false) if manager.is_disabled(&url) => {
+                                    reply_err!(tx, "ERR Feed is disabled, use feed resume: {}", feed)
                                 }
+                                Ok(false) => reply_ok!(tx, "ACK Already following feed: {}", feed),
                                 Err(e) => {
                                     reply_err!(tx, "ERR Could not add feed: {:?}", e);
                                     continue;
@@ -102,17 +331,373 @@ pub async fn start(cfg: AppConfig) -> Result<(), Box<dyn std::error::Error + Sen
                             }
                         },
 
+                        ServerCommand::AddFeed(feeds, options) => {
+                            let specs: Result<Vec<FeedSpec>, String> = feeds
+                                .iter()
+                                .map(|feed| FeedSpec::with_options(feed.clone(), &options))
+                                .collect();
+                            let specs = match specs {
+                                Ok(specs) => specs,
+                                Err(e) => {
+                                    reply_err!(tx, "ERR Invalid feed option: {}", e);
+                                    continue;
+                                }
+                            };
+                            let (mut added, mut skipped, mut failed) = (0, 0, Vec::new());
+                            for (feed, spec) in feeds.into_iter().zip(specs) {
+                                match manager.follow_feed(spec, &options).await {
+                                    Ok(true) => added += 1,
+                                    Ok(false) => skipped += 1,
+                                    Err(e) => {
+                                        warn!("Could not add feed {}: {:?}", redact_url(&feed), e);
+                                        failed.push(feed);
+                                    }
+                                }
+                            }
+                            reply_ok!(
+                                tx,
+                                "ACK Added {} feeds, skipped {}, failed {}: {}",
+                                added,
+                                skipped,
+                                failed.len(),
+                                failed.join(", ")
+                            );
+                        },
+
                         ServerCommand::RemoveFeed(feed) => {
-                            if !manager.remove_feed(&feed).await {
-                                reply_err!(tx, "ERR Feed is not being followed");
+                            match manager.remove_feed(&feed).await {
+                                RemoveOutcome::Stopped => reply_ok!(tx, "ACK Removed {} feed", feed),
+                                RemoveOutcome::DatabaseOnly => reply_ok!(
+                                    tx,
+                                    "ACK Removed {} feed from the database, it was not being followed",
+                                    feed
+                                ),
+                                RemoveOutcome::NotFound => {
+                                    reply_err!(tx, "ERR Feed is not being followed")
+                                }
+                            }
+                        },
+
+                        ServerCommand::PauseFeed(feed) => {
+                            if manager.set_paused(&feed, true).await {
+                                reply_ok!(tx, "ACK Paused feed: {}", feed)
+                            } else {
+                                reply_err!(tx, "ERR Feed is not being followed: {}", feed)
+                            }
+                        },
+
+                        ServerCommand::ResumeFeed(feed) => {
+                            if manager.is_paused(&feed) {
+                                manager.set_paused(&feed, false).await;
+                                reply_ok!(tx, "ACK Resumed feed: {}", feed);
+                                continue;
+                            }
+                            // Prefer the configured options for this feed if it came from the config,
+                            // then the ones it was added with
+                            let spec = feed_spec(&cfg, manager.store(), canonical_url(&feed)).await;
+                            match manager.resume_feed(spec).await {
+                                Ok(true) => reply_ok!(tx, "ACK Resumed feed: {}", feed),
+                                Ok(false) => reply_err!(tx, "ERR Feed is not disabled: {}", feed),
+                                Err(e) => reply_err!(tx, "ERR Could not resume feed: {:?}", e),
+                            }
+                        },
+
+                        ServerCommand::Status => {
+                            let snapshot = manager.snapshot();
+                            let paused: Vec<&str> =
+                                snapshot.iter().filter(|f| f.paused).map(|f| f.url.as_str()).collect();
+                            let stale: Vec<&str> =
+                                snapshot.iter().filter(|f| f.stale).map(|f| f.url.as_str()).collect();
+                            let dormant = manager.dormant_feeds().await;
+                            let disabled = manager.disabled_feeds();
+                            let starting = manager.starting_feeds();
+                            let (depth, capacity) = manager.queue_depth();
+                            if json {
+                                let status = json!({
+                                    "active": manager.len(),
+                                    "queue": { "depth": depth, "capacity": capacity },
+                                    "paused": paused,
+                                    "stale": stale,
+                                    "dormant": dormant
+                                        .iter()
+                                        .map(|(url, newest)| json!({ "url": url, "newest": newest.to_rfc3339() }))
+                                        .collect::<Vec<_>>(),
+                                    "disabled": disabled,
+                                    "starting": starting,
+                                });
+                                reply_ok!(tx, "ACK {}", status);
+                                continue;
+                            }
+                            reply_ok!(
+                                tx,
+                                "ACK {} active feeds, queue {}/{}, {} paused feeds: {}, {} stale feeds: {}, {} dormant feeds: {}, {} disabled feeds: {}, {} feeds failing to start: {}",
+                                manager.len(),
+                                depth,
+                                capacity,
+                                paused.len(),
+                                paused.join(", "),
+                                stale.len(),
+                                stale.join(", "),
+                                dormant.len(),
+                                dormant
+                                    .iter()
+                                    .map(|(url, newest)| format!("{} (since {})", url, newest.format("%Y-%m-%d")))
+                                    .collect::<Vec<_>>()
+                                    .join(", "),
+                                disabled.len(),
+                                disabled.join(", "),
+                                starting.len(),
+                                starting.join(", ")
+                            );
+                        },
+
+                        ServerCommand::TestWebhook(feed) => {
+                            let (url, color) = match resolve_webhook(&cfg, manager.store(), feed.as_deref()).await {
+                                Ok(destination) => destination,
+                                Err(e) => {
+                                    reply_err!(tx, "ERR {}", e);
+                                    continue;
+                                }
+                            };
+                            let payload = build_payload(
+                                "korvatunturi-rss",
+                                "Test notification",
+                                "This is a test notification from korvatunturi-rss",
+                                "https://github.com/zwsyscall/korvatunturi-rss",
+                                &cfg.embed,
+                                color,
+                            );
+                            if dry_run {
+                                reply_ok!(tx, "ACK Dry run, not sending: {}", payload);
+                                continue;
+                            }
+                            let request = webhook_request(&client, &url, &payload, cfg.webhook_secret.as_deref());
+                            match request.send().await {
+                                Ok(res) if res.status().is_success() => {
+                                    reply_ok!(tx, "ACK Webhook responded with {}", res.status())
+                                }
+                                Ok(res) => reply_err!(tx, "ERR Webhook responded with {}", res.status()),
+                                Err(e) => reply_err!(tx, "ERR Webhook delivery failed: {}", e.without_url()),
+                            }
+                        },
+
+                        ServerCommand::Recent(feed, n) => {
+                            let items = manager.store().recent_items(feed.map(|f| canonical_url(&f)).as_deref(), n as u32).await;
+                            if json {
+                                let items: Vec<Value> = items.iter().map(item_json).collect();
+                                reply_ok!(tx, "ACK {}", json!(items));
+                                continue;
+                            }
+                            let lines: Vec<String> = items
+                                .iter()
+                                .map(|i| {
+                                    let mut line = format!(
+                                        "{} | {} | {}",
+                                        i.pub_date,
+                                        i.title.as_deref().unwrap_or("<no title>"),
+                                        i.link.as_deref().unwrap_or("<no link>")
+                                    );
+                                    if let Some(enclosure) = &i.enclosure_url {
+                                        line.push_str(&format!(" | {}", enclosure));
+                                    }
+                                    line
+                                })
+                                .collect();
+                            reply_ok!(tx, "ACK {} recent items:\n{}", lines.len(), lines.join("\n"));
+                        },
+
+                        ServerCommand::Ping => {
+                            reply_ok!(tx, "ACK Pong, up {}", format_uptime(started.elapsed()))
+                        },
+
+                        ServerCommand::FeedStats => {
+                            let stats = manager.store().item_stats().await;
+                            let mut feeds = manager.feeds();
+                            feeds.extend(manager.disabled_feeds());
+                            feeds.sort();
+                            // Feeds that never produced anything are not in the archive at all
+                            let rows: Vec<(&String, i64, Option<&str>)> = feeds
+                                .iter()
+                                .map(|f| match stats.get(f) {
+                                    Some(s) => (f, s.items, Some(s.latest.as_str())),
+                                    None => (f, 0, None),
+                                })
+                                .collect();
+                            // Also counts items of feeds that are no longer followed
+                            let total: i64 = stats.values().map(|s| s.items).sum();
+                            if json {
+                                let feeds: Vec<_> = rows
+                                    .iter()
+                                    .map(|(url, items, latest)| json!({ "url": url, "items": items, "latest": latest }))
+                                    .collect();
+                                reply_ok!(tx, "ACK {}", json!({ "feeds": feeds, "total": total }));
+                                continue;
+                            }
+                            let lines: Vec<String> = rows
+                                .iter()
+                                .map(|(url, items, latest)| format!("{}\t{}\t{}", items, latest.unwrap_or("never"), url))
+                                .collect();
+                            reply_ok!(
+                                tx,
+                                "ACK {} archived items, item counts for {} feeds:\n{}",
+                                total,
+                                lines.len(),
+                                lines.join("\n")
+                            );
+                        },
+
+                        ServerCommand::ForgetFeed(feed, keep_archive) => {
+                            let url = canonical_url(&feed);
+                            // A running feed would keep suppressing items from its in-memory cache
+                            if manager.feeds().contains(&url) {
+                                reply_err!(tx, "ERR Feed is still followed, remove it first: {}", feed);
+                                continue;
+                            }
+                            match manager.store().forget_feed(&url, keep_archive).await {
+                                Ok((seen, archived)) => reply_ok!(
+                                    tx,
+                                    "ACK Forgot {}: removed {} seen ids and {} archived items",
+                                    feed,
+                                    seen,
+                                    archived
+                                ),
+                                Err(e) => reply_err!(tx, "ERR Could not forget feed: {}", e),
+                            }
+                        },
+
+                        ServerCommand::Resend(key) => {
+                            let Some(archived) = manager.store().find_item(&key).await else {
+                                reply_err!(tx, "ERR No archived item matches {}", key);
+                                continue;
+                            };
+                            let spec = feed_spec(&cfg, manager.store(), archived.feed_source.clone()).await;
+                            let feed_title = manager
+                                .store()
+                                .feed_records()
+                                .await
+                                .remove(&archived.feed_source)
+                                .and_then(|record| record.title);
+                            let event = FeedEvent {
+                                item: archived.to_item(),
+                                feed_title,
+                                webhook: spec.webhook,
+                                color: spec.color,
+                                id: archived.id,
+                                source: archived.feed_source,
+                                digest: Vec::new(),
+                            };
+                            reply_ok!(tx, "ACK Queued item {} from {} for delivery", event.id, event.source);
+                            dispatcher.dispatch(event).await;
+                        },
+
+                        ServerCommand::Vacuum => match manager.store().vacuum().await {
+                            Ok((before, after)) => reply_ok!(
+                                tx,
+                                "ACK Vacuumed database from {} to {} bytes, reclaimed {}",
+                                before,
+                                after,
+                                before - after
+                            ),
+                            Err(e) => reply_err!(tx, "ERR Vacuum failed: {}", e),
+                        },
+
+                        ServerCommand::Search(query) => {
+                            let items = manager.store().search(&query, SEARCH_LIMIT).await;
+                            if json {
+                                let items: Vec<Value> = items.iter().map(item_json).collect();
+                                reply_ok!(tx, "ACK {}", json!(items));
                                 continue;
                             }
-                            reply_ok!(tx, "ACK Removed {} feed", feed);
+                            let lines: Vec<String> = items
+                                .iter()
+                                .map(|i| {
+                                    format!(
+                                        "{} | {}",
+                                        i.title.as_deref().unwrap_or("<no title>"),
+                                        i.link.as_deref().unwrap_or("<no link>")
+                                    )
+                                })
+                                .collect();
+                            reply_ok!(tx, "ACK {} matching items:\n{}", lines.len(), lines.join("\n"));
                         },
 
                         ServerCommand::GetFeeds => {
-                            let feeds = manager.feeds().join(", ");
-                            reply_ok!(tx, "ACK Returning feeds: {}", &feeds)
+                            if json {
+                                let feeds: Vec<_> = manager
+                                    .snapshot()
+                                    .iter()
+                                    .map(|f| {
+                                        json!({
+                                            "url": f.url,
+                                            "title": f.title,
+                                            "paused": f.paused,
+                                            "last_success": f.last_success.map(|t| t.to_rfc3339()),
+                                            "failures": f.failures,
+                                            "stale": f.stale,
+                                        })
+                                    })
+                                    .collect();
+                                reply_ok!(tx, "ACK {}", json!(feeds));
+                                continue;
+                            }
+                            let lines: Vec<String> = manager
+                                .snapshot()
+                                .iter()
+                                .map(|f| {
+                                    let last = f
+                                        .last_success
+                                        .map(|t| t.to_rfc3339())
+                                        .unwrap_or_else(|| "never".to_string());
+                                    let paused = match (f.paused, f.stale) {
+                                        (true, true) => " (paused, stale)",
+                                        (true, false) => " (paused)",
+                                        (false, true) => " (stale)",
+                                        (false, false) => "",
+                                    };
+                                    let title = f.title.as_deref().map(|t| format!(" \"{}\"", t)).unwrap_or_default();
+                                    format!(
+                                        "{}{}{} | last refresh {} | {} failures",
+                                        f.url, title, paused, last, f.failures
+                                    )
+                                })
+                                .collect();
+                            reply_ok!(tx, "ACK Following {} feeds:\n{}", lines.len(), lines.join("\n"))
+                        },
+
+                        ServerCommand::Reload => {
+                            let desired = cfg.feeds.get();
+                            // Disabled feeds count as known, re-adding them would run them while they
+                            // are still flagged disabled
+                            let (to_add, _) = diff_feeds(&manager.known_feeds(), &desired);
+                            // Only drop feeds that came from the config, runtime additions stay
+                            let (_, to_remove) = diff_feeds(&config_feeds, &desired);
+
+                            let (mut added, mut removed, mut failed) = (0, 0, 0);
+                            for spec in to_add {
+                                let url = spec.url.clone();
+                                match manager.add_feed(spec).await {
+                                    Ok(true) => added += 1,
+                                    Ok(false) => {}
+                                    Err(e) => {
+                                        warn!("Reload could not add feed {}: {:?}", redact_url(&url), e);
+                                        failed += 1;
+                                    }
+                                }
+                            }
+                            for url in to_remove {
+                                if !matches!(manager.remove_feed(&url).await, RemoveOutcome::NotFound) {
+                                    removed += 1;
+                                }
+                            }
+                            config_feeds = desired.into_iter().map(|f| f.url).collect();
+
+                            reply_ok!(
+                                tx,
+                                "ACK Reloaded feeds: {} added, {} removed, {} failed",
+                                added,
+                                removed,
+                                failed
+                            );
                         },
 
                         _ => {
@@ -130,14 +715,54 @@ pub async fn start(cfg: AppConfig) -> Result<(), Box<dyn std::error::Error + Sen
     }
 }
 
-fn create_ipc_listener(
+/// `auth_token`, when set, has to be sent as the first line of every connection. It is required
+/// when `tcp_addr` is not a loopback address. At most
+/// `queue_size` commands wait for the server loop, clients beyond that are turned away as busy.
+/// The read-only HTTP API on `api_addr` only asks for the token as a bearer token when it is not on
+/// a loopback address.
+async fn create_ipc_listener(
     socket_name: &str,
+    tcp_addr: Option<&str>,
+    auth_token: Option<Arc<str>>,
+    queue_size: usize,
+    api_addr: Option<&str>,
 ) -> Result<mpsc::Receiver<CommandMessage>, Box<dyn std::error::Error + Send + Sync>> {
-    let name = socket_name.to_ns_name::<GenericNamespaced>()?;
-    let listener = ListenerOptions::new().name(name).create_tokio()?;
-    let (command_send, command_recv) = mpsc::channel(300);
+    let (command_send, command_recv) = mpsc::channel(queue_size.max(1));
+    if let Some(addr) = api_addr {
+        api::serve(addr, command_send.clone(), auth_token.clone()).await?;
+    }
+
+    // A crashed daemon can leave its socket file behind. It is only replaced when nothing answers
+    // on it, so a second daemon cannot take over the socket of a running one.
+    match Stream::connect(local_socket_name(socket_name)?).await {
+        Ok(_) => {
+            return Err(format!("Another instance is already listening on {}", socket_name).into());
+        }
+        // Refused or missing means nobody is listening and a leftover file can be replaced
+        Err(e)
+            if matches!(
+                e.kind(),
+                std::io::ErrorKind::ConnectionRefused | std::io::ErrorKind::NotFound
+            ) =>
+        {
+            debug!("No daemon answers on {}, taking it over", socket_name);
+        }
+        // Anything else, e.g. a socket owned by another user, cannot be told apart from a live
+        // daemon, so it is left alone
+        Err(e) => {
+            return Err(format!("Could not check socket {}: {}", socket_name, e).into());
+        }
+    }
+    let listener = ListenerOptions::new()
+        .name(local_socket_name(socket_name)?)
+        .try_overwrite(true)
+        // Socket files are removed again when the listener is dropped on shutdown
+        .reclaim_name(true)
+        .create_tokio()?;
 
     info!("Listening for commands on {}", socket_name);
+    let tx = command_send.clone();
+    let token = auth_token.clone();
     tokio::spawn(async move {
         loop {
             let conn = match listener.accept().await {
@@ -148,69 +773,348 @@ fn create_ipc_listener(
                 }
             };
 
-            let tx = command_send.clone();
-            tokio::spawn(handle_connection(conn, tx));
+            tokio::spawn(handle_connection(conn, tx.clone(), token.clone()));
         }
     });
 
+    // TCP is served alongside the local socket, both feed the same command queue
+    if let Some(addr) = tcp_addr {
+        let listener = TcpListener::bind(addr).await?;
+        let local = listener.local_addr()?;
+        // Anyone who can reach the port could otherwise add feeds or send webhooks
+        if !local.ip().is_loopback() {
+            if auth_token.is_none() {
+                return Err(format!(
+                    "Refusing to serve commands on non-loopback address {} without an auth_token",
+                    local
+                )
+                .into());
+            }
+            warn!(
+                "Command interface is reachable on non-loopback address {}",
+                local
+            );
+        }
+
+        info!("Listening for commands on tcp://{}", local);
+        tokio::spawn(async move {
+            loop {
+                let conn = match listener.accept().await {
+                    Ok((c, peer)) => {
+                        debug!("Accepted TCP connection from {}", peer);
+                        c
+                    }
+                    Err(e) => {
+                        error!("Error accepting connection: {}", e);
+                        continue;
+                    }
+                };
+
+                tokio::spawn(handle_connection(
+                    conn,
+                    command_send.clone(),
+                    auth_token.clone(),
+                ));
+            }
+        });
+    }
+
     Ok(command_recv)
 }
 
-async fn handle_connection(conn: Stream, command_tx: mpsc::Sender<CommandMessage>) {
-    let mut recver = BufReader::new(&conn);
-    let mut sender = &conn;
-    let mut buffer = String::with_capacity(2048);
+/// Compares tokens through their digests so the time taken does not depend on where they differ
+pub(super) fn token_matches(given: &str, expected: &str) -> bool {
+    let (given, expected) = (Sha256::digest(given), Sha256::digest(expected));
+    given
+        .iter()
+        .zip(expected.iter())
+        .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+        == 0
+}
 
-    // Reads input from the client
-    buffer.clear();
-    match recver.read_line(&mut buffer).await {
-        Ok(0) => {
+/// Reads the next message from a client, `None` if it disconnected or sent garbage
+async fn read_client_message<R>(reader: &mut R) -> Option<(String, bool)>
+where
+    R: AsyncBufRead + Unpin,
+{
+    match read_message(reader).await {
+        Ok(None) => {
             debug!("Client disconnected");
-            return;
+            None
         }
-        Ok(_) => {}
+        Ok(message) => message,
         Err(e) => {
             error!("Failed to read from client: {}", e);
+            None
+        }
+    }
+}
+
+/// Answers in the framing the client used, plain replies are read until the connection closes
+async fn send_reply<W>(writer: &mut W, reply: &str, framed: bool) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    if framed {
+        write_frame(writer, reply).await
+    } else {
+        writer.write_all(reply.as_bytes()).await
+    }
+}
+
+/// Serves commands until the client disconnects. Framed clients may send any number of commands
+/// on one connection, a plain line is answered once and the connection closed since plain replies
+/// end there.
+async fn handle_connection<S>(
+    conn: S,
+    command_tx: mpsc::Sender<CommandMessage>,
+    auth_token: Option<Arc<str>>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (reader, mut sender) = tokio::io::split(conn);
+    let mut recver = BufReader::new(reader);
+
+    // With an auth token the first message is the token and the commands follow it
+    if let Some(expected) = auth_token.as_deref() {
+        let Some((token, framed)) = read_client_message(&mut recver).await else {
+            return;
+        };
+        if !token_matches(&token, expected) {
+            warn!("Rejected command with a missing or wrong auth token");
+            let _ = send_reply(&mut sender, "ERR unauthorized\n", framed).await;
+            return;
+        }
+    }
+
+    while let Some((message, framed)) = read_client_message(&mut recver).await {
+        let (reply, server_alive) = serve_command(&command_tx, &message).await;
+        if let Err(e) = send_reply(&mut sender, &reply, framed).await {
+            error!("Failed to send reply to client: {}", e);
+            return;
+        }
+        if !framed || !server_alive {
             return;
         }
     }
-    debug!("Client sent: {}", &buffer.trim());
+}
+
+/// Forwards one command message to the server loop and returns the reply to send, along with
+/// whether the server loop is still taking commands
+async fn serve_command(command_tx: &mpsc::Sender<CommandMessage>, message: &str) -> (String, bool) {
+    debug!("Client sent: {}", message.trim());
+    let (json, line) = strip_json_marker(message.trim());
+    let render = |reply: &str| {
+        if json {
+            json_reply(reply)
+        } else {
+            reply.to_string()
+        }
+    };
 
     // Parse input
-    let cmd = match ServerCommand::try_from(buffer.trim().to_string()) {
+    let cmd = match ServerCommand::try_from(line.to_string()) {
         Ok(c) => c,
         Err(e) => {
             error!("Error converting buffer to command: {:?}", e);
-            let _ = sender.write_all(b"ERR invalid command\n").await;
-            return;
+            return (render(&format!("ERR Invalid command: {}\n", e)), true);
         }
     };
 
-    // Send upstream
+    // Send upstream. Not waiting for room keeps connections from piling up while the loop is busy.
     let (reply_tx, reply_rx) = oneshot::channel::<String>();
-    if command_tx
-        .send(CommandMessage {
-            cmd,
-            reply: reply_tx,
-        })
-        .await
-        .is_err()
-    {
-        error!("Failed to forward command to server");
-        let _ = sender.write_all(b"ERR internal\n").await;
-        return;
+    match command_tx.try_send(CommandMessage {
+        cmd,
+        reply: reply_tx,
+        json,
+    }) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => {
+            warn!("Command queue full, turning a client away");
+            return (render("ERR busy, try again\n"), true);
+        }
+        Err(TrySendError::Closed(_)) => {
+            error!("Failed to forward command to server");
+            return (render("ERR internal\n"), false);
+        }
     }
 
     // Waits for a reply from the upstream server
     match reply_rx.await {
-        Ok(reply) => {
-            if let Err(e) = sender.write_all(reply.as_bytes()).await {
-                error!("Failed to send reply to client: {}", e);
-            }
-        }
+        Ok(reply) => (render(&reply), true),
         Err(_canceled) => {
             error!("Reply channel dropped before sending response");
-            let _ = sender.write_all(b"ERR no-reply\n").await;
+            (render("ERR no-reply\n"), true)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{io::AsyncReadExt, net::TcpStream};
+
+    /// Stands in for the server loop and acknowledges every command by echoing it
+    fn echo_loop() -> mpsc::Sender<CommandMessage> {
+        let (tx, mut rx) = mpsc::channel::<CommandMessage>(4);
+        tokio::spawn(async move {
+            while let Some(message) = rx.recv().await {
+                let _ = message.reply.send(format!("ACK {}\n", message.cmd));
+            }
+        });
+        tx
+    }
+
+    /// Client end of a TCP connection served by `handle_connection`
+    async fn connect(
+        commands: mpsc::Sender<CommandMessage>,
+        auth_token: Option<&str>,
+    ) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (conn, _) = listener.accept().await.unwrap();
+        tokio::spawn(handle_connection(conn, commands, auth_token.map(Arc::from)));
+        client
+    }
+
+    async fn read_to_end(conn: &mut TcpStream) -> String {
+        let mut reply = String::new();
+        conn.read_to_string(&mut reply).await.unwrap();
+        reply
+    }
+
+    #[tokio::test]
+    async fn answers_a_plain_line_over_tcp() {
+        let mut conn = connect(echo_loop(), None).await;
+        conn.write_all(b"ping\n").await.unwrap();
+        assert_eq!(read_to_end(&mut conn).await, "ACK ping\n");
+
+        let mut conn = connect(echo_loop(), None).await;
+        conn.write_all(b"json version\n").await.unwrap();
+        let reply: serde_json::Value = serde_json::from_str(&read_to_end(&mut conn).await).unwrap();
+        assert_eq!(reply, json!({"status": "ok", "data": "version"}));
+    }
+
+    #[tokio::test]
+    async fn parse_errors_reach_the_client() {
+        let (tx, mut rx) = mpsc::channel(4);
+        let mut conn = connect(tx, None).await;
+        conn.write_all(b"feed add\n").await.unwrap();
+        assert_eq!(
+            read_to_end(&mut conn).await,
+            "ERR Invalid command: Missing link\n"
+        );
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn token_matches_only_the_exact_token() {
+        assert!(token_matches("s3cret", "s3cret"));
+        assert!(!token_matches("s3cret ", "s3cret"));
+        assert!(!token_matches("", "s3cret"));
+    }
+
+    #[tokio::test]
+    async fn commands_need_the_auth_token_first() {
+        let mut conn = connect(echo_loop(), Some("s3cret")).await;
+        write_frame(&mut conn, "s3cret").await.unwrap();
+        write_frame(&mut conn, "ping").await.unwrap();
+        let mut reader = BufReader::new(conn);
+        assert_eq!(
+            read_message(&mut reader).await.unwrap(),
+            Some(("ACK ping\n".to_string(), true))
+        );
+
+        // A wrong token is turned away without running the command that follows it
+        let (tx, mut rx) = mpsc::channel(4);
+        let mut conn = connect(tx, Some("s3cret")).await;
+        conn.write_all(b"guess\nping\n").await.unwrap();
+        assert_eq!(read_to_end(&mut conn).await, "ERR unauthorized\n");
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn full_queue_answers_busy_instead_of_waiting() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let (reply, _) = oneshot::channel();
+        tx.try_send(CommandMessage {
+            cmd: ServerCommand::Ping,
+            reply,
+            json: false,
+        })
+        .unwrap();
+
+        assert_eq!(
+            serve_command(&tx, "ping").await,
+            ("ERR busy, try again\n".to_string(), true)
+        );
+        assert!(rx.recv().await.is_some());
+
+        rx.close();
+        assert_eq!(
+            serve_command(&tx, "ping").await,
+            ("ERR internal\n".to_string(), false)
+        );
+    }
+
+    #[tokio::test]
+    async fn framed_clients_send_several_commands_on_one_connection() {
+        let mut conn = connect(echo_loop(), None).await;
+        write_frame(&mut conn, "ping").await.unwrap();
+        write_frame(&mut conn, "json status").await.unwrap();
+        let mut reader = BufReader::new(conn);
+
+        assert_eq!(
+            read_message(&mut reader).await.unwrap(),
+            Some(("ACK ping\n".to_string(), true))
+        );
+        let (reply, framed) = read_message(&mut reader).await.unwrap().unwrap();
+        assert!(framed);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&reply).unwrap(),
+            json!({"status": "ok", "data": "status"})
+        );
+
+        // The connection stays open for more until the client hangs up
+        write_frame(reader.get_mut(), "version").await.unwrap();
+        assert_eq!(
+            read_message(&mut reader).await.unwrap(),
+            Some(("ACK version\n".to_string(), true))
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn socket_is_taken_over_after_shutdown_or_crash() {
+        let path =
+            std::env::temp_dir().join(format!("korvatunturi-ipc-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = path.to_str().unwrap();
+        let runtime = || {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+        };
+        let listen = || create_ipc_listener(socket, None, None, 4, None);
+
+        let daemon = runtime();
+        daemon.block_on(async {
+            listen().await.unwrap();
+            let err = listen().await.unwrap_err();
+            assert!(err.to_string().contains("Another instance"), "{}", err);
+        });
+        // Shutting down drops the accept loop and its listener removes the socket file
+        drop(daemon);
+        assert!(!path.exists());
+        runtime().block_on(async { listen().await.unwrap() });
+
+        // A crashed daemon leaves the file behind with nobody listening on it
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        runtime().block_on(async { listen().await.unwrap() });
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,21 @@
+// Readiness notifications for `Type=notify` systemd units. Every call is a no-op when
+// `NOTIFY_SOCKET` is not set or the platform has no systemd.
+
+/// Tells systemd the daemon is watching feeds and accepting commands
+pub fn notify_ready() {
+    #[cfg(unix)]
+    notify(sd_notify::NotifyState::Ready);
+}
+
+/// Tells systemd a graceful shutdown has started
+pub fn notify_stopping() {
+    #[cfg(unix)]
+    notify(sd_notify::NotifyState::Stopping);
+}
+
+#[cfg(unix)]
+fn notify(state: sd_notify::NotifyState) {
+    if let Err(e) = sd_notify::notify(false, &[state]) {
+        log::warn!("Could not notify systemd: {}", e);
+    }
+}